@@ -0,0 +1,85 @@
+//! Cost of the per-buffer work done on the data thread.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use rust_audio_monitor::{
+    AudioProcessor, DTMF_COLUMNS, DTMF_ROWS, ProcessorConfig, SampleFormat, Signal, SignalGenerator,
+};
+use std::hint::black_box;
+
+const RATE: u32 = 48000;
+const CHANNELS: usize = 2;
+const FRAME_SIZES: [usize; 3] = [512, 2048, 8192];
+
+/// A stereo 440 Hz sine, `n_frames` long.
+fn signal(n_frames: usize) -> Vec<f32> {
+    let mut samples = vec![0.0; n_frames * CHANNELS];
+    SignalGenerator::new(Signal::Sine(440.0), RATE, 0.5).fill(&mut samples, CHANNELS);
+    samples
+}
+
+fn process_frame(c: &mut Criterion) {
+    let configs = [
+        ("levels", ProcessorConfig::default()),
+        (
+            "dtmf",
+            ProcessorConfig {
+                tones: DTMF_ROWS.into_iter().chain(DTMF_COLUMNS).collect(),
+                ..Default::default()
+            },
+        ),
+        (
+            "pitch",
+            ProcessorConfig {
+                pitch: true,
+                ..Default::default()
+            },
+        ),
+    ];
+    let mut group = c.benchmark_group("process_frame");
+    for (name, config) in configs {
+        for n_frames in FRAME_SIZES {
+            // at unity gain the conditioning leaves a sine as it is, so the
+            // buffer can be reused for every iteration
+            let mut samples = signal(n_frames);
+            let mut processor = AudioProcessor::new(config.clone());
+            processor.configure(CHANNELS, RATE).unwrap();
+            processor.reserve(n_frames);
+            group.throughput(Throughput::Elements(n_frames as u64));
+            group.bench_function(BenchmarkId::new(name, n_frames), |b| {
+                b.iter(|| {
+                    black_box(processor.process_frame(black_box(&mut samples), CHANNELS, RATE));
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for format in [SampleFormat::F32, SampleFormat::S32, SampleFormat::S16] {
+        for n_frames in FRAME_SIZES {
+            let bytes = vec![0x5a; n_frames * CHANNELS * format.size()];
+            let mut samples = vec![0.0; n_frames * CHANNELS];
+            group.throughput(Throughput::Elements(n_frames as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("{:?}", format), n_frames),
+                &bytes,
+                |b, bytes| {
+                    b.iter(|| {
+                        for (sample, bytes) in
+                            samples.iter_mut().zip(bytes.chunks_exact(format.size()))
+                        {
+                            *sample = format.decode(bytes);
+                        }
+                        black_box(&samples);
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, process_frame, decode);
+criterion_main!(benches);
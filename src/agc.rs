@@ -0,0 +1,68 @@
+//! Automatic gain control.
+
+use crate::envelope::EnvelopeFollower;
+
+/// Ramps its gain so the RMS level of a stream approaches a target.
+///
+/// The level is tracked per buffer with separate attack and release time
+/// constants, and each buffer ramps linearly from the previous gain to the
+/// new one so gain changes don't step audibly.
+#[derive(Clone, Debug)]
+pub struct AutoGain {
+    /// Target RMS, linear.
+    target: f32,
+    /// Highest gain applied, linear, so silence isn't pulled up into noise.
+    max_gain: f32,
+    /// Smoothed RMS over all channels.
+    envelope: EnvelopeFollower,
+    gain: f32,
+}
+
+impl AutoGain {
+    /// Bring the RMS level towards `target_db` dBFS, following rises in
+    /// level with time constant `attack_secs` and falls with
+    /// `release_secs`, amplifying by at most `max_gain_db`.
+    pub fn new(target_db: f32, attack_secs: f32, release_secs: f32, max_gain_db: f32) -> Self {
+        AutoGain {
+            target: 10f32.powf(target_db / 20.0),
+            max_gain: 10f32.powf(max_gain_db / 20.0),
+            envelope: EnvelopeFollower::new(attack_secs, release_secs),
+            gain: 1.0,
+        }
+    }
+
+    /// Gain applied at the end of the last buffer, linear.
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Apply the gain to one buffer of interleaved samples in place.
+    pub fn process(&mut self, samples: &mut [f32], n_channels: usize, rate: u32) {
+        let n_frames = samples.len() / n_channels.max(1);
+        if n_frames == 0 || rate == 0 {
+            return;
+        }
+        let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+        let envelope = self.envelope.process(rms, n_frames as f32 / rate as f32);
+
+        let from = self.gain;
+        let to = if envelope > 0.0 {
+            (self.target / envelope).min(self.max_gain)
+        } else {
+            self.max_gain
+        };
+        for (i, frame) in samples.chunks_exact_mut(n_channels).enumerate() {
+            let gain = from + (to - from) * (i + 1) as f32 / n_frames as f32;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+        self.gain = to;
+    }
+
+    /// Start over at unity gain, as if no buffer had been seen.
+    pub fn reset(&mut self) {
+        self.envelope.reset();
+        self.gain = 1.0;
+    }
+}
@@ -0,0 +1,472 @@
+//! Everything a decoded buffer goes through before its levels are reported.
+
+use crate::agc::AutoGain;
+use crate::filter::{FilterChain, FilterSpec, Preemphasis};
+use crate::processor::{AudioProcessor, ConfigError, Level, ProcessorConfig};
+use crate::profile::{Profile, Stage};
+use crate::record::WavRecorder;
+use crate::sample::sanitize;
+use std::mem;
+use std::time::Instant;
+
+/// Settings for an [`Analyzer`].
+#[derive(Clone, Debug, Default)]
+pub struct AnalyzerConfig {
+    pub processor: ProcessorConfig,
+    /// Filters run over every channel before the processor, in order.
+    pub filters: Vec<FilterSpec>,
+    /// Pre-emphasis coefficient, see [`Preemphasis`]; 0 disables it.
+    pub preemphasis: f32,
+    /// Gain control applied after the filters, before the processor.
+    pub agc: Option<AutoGain>,
+    /// Gather buffers and analyze them together at most this many times a
+    /// second.
+    pub max_fps: Option<f64>,
+    /// Frames to analyze after each format change without reporting them,
+    /// so the filters and AGC settle first.
+    pub warmup: u64,
+    /// Stop after analyzing exactly this many frames, not counting the
+    /// warm-up.
+    pub max_frames: Option<u64>,
+    /// Time each stage, see [`Analyzer::profile`].
+    pub profile: bool,
+}
+
+/// The results of one analysis, handed to the callback of
+/// [`Analyzer::push`].
+pub struct Analysis<'a> {
+    /// The interleaved samples that were analyzed, filtered and
+    /// conditioned by the processor.
+    pub samples: &'a [f32],
+    pub n_channels: usize,
+    pub rate: u32,
+    pub levels: &'a [Level],
+    /// The processor, for everything measured besides the levels.
+    pub processor: &'a AudioProcessor,
+    /// Linear AGC gain applied to the samples, `None` without AGC.
+    pub agc_gain: Option<f32>,
+    /// NaN or infinite samples replaced with silence since the last
+    /// analysis.
+    pub non_finite: usize,
+    /// Whether these are warm-up frames, which are analyzed but not meant
+    /// to be reported.
+    pub warmup: bool,
+}
+
+/// Sanitizes, filters, gain-controls and analyzes buffers of interleaved
+/// samples, gathering them into longer periods with
+/// [`AnalyzerConfig::max_fps`]. With a [`WavRecorder`] set, the input is
+/// recorded as it comes in, before any of that.
+///
+/// Once [`configure`](Self::configure)d for a format, pushing buffers of up
+/// to the frames it was configured for doesn't allocate, so it is safe to
+/// drive from a real-time thread.
+pub struct Analyzer {
+    processor: AudioProcessor,
+    filters: FilterChain,
+    preemphasis: Preemphasis,
+    agc: Option<AutoGain>,
+    max_fps: Option<f64>,
+    /// Frames to gather before analyzing them together with `max_fps`, 0 to
+    /// analyze every buffer as it comes.
+    period_frames: usize,
+    /// Samples gathered towards the next analysis. Reserved for a whole
+    /// period plus one buffer when the format is configured.
+    pending: Vec<f32>,
+    warmup: u64,
+    warmup_left: u64,
+    /// Frames still to analyze with `max_frames`.
+    frames_left: Option<u64>,
+    /// NaN or infinite samples replaced since the last analysis.
+    non_finite: usize,
+    profile: Option<Profile>,
+    /// Gets every analyzed frame as it was pushed, only sanitized.
+    recorder: Option<WavRecorder>,
+    n_channels: usize,
+    rate: u32,
+    /// Largest buffer configured for.
+    max_frames: usize,
+}
+
+impl Analyzer {
+    pub fn new(config: AnalyzerConfig) -> Self {
+        Analyzer {
+            processor: AudioProcessor::new(config.processor),
+            filters: FilterChain::new(config.filters),
+            preemphasis: Preemphasis::new(config.preemphasis),
+            agc: config.agc,
+            max_fps: config.max_fps,
+            period_frames: 0,
+            pending: Vec::new(),
+            warmup: config.warmup,
+            warmup_left: config.warmup,
+            frames_left: config.max_frames,
+            non_finite: 0,
+            profile: config.profile.then(Profile::new),
+            recorder: None,
+            n_channels: 0,
+            rate: 0,
+            max_frames: 0,
+        }
+    }
+
+    /// Prepare for a stream of `n_channels` at `rate`, in buffers of up to
+    /// `max_frames`, and start the analysis over: filter history, AGC and
+    /// warm-up. Only `max_frames` carries on. Call this whenever the format
+    /// changes.
+    pub fn configure(
+        &mut self,
+        n_channels: usize,
+        rate: u32,
+        max_frames: usize,
+    ) -> Result<(), ConfigError> {
+        self.processor.configure(n_channels, rate)?;
+        self.filters.configure(n_channels, rate)?;
+        self.preemphasis.configure(n_channels);
+        if let Some(agc) = &mut self.agc {
+            agc.reset();
+        }
+        self.n_channels = n_channels;
+        self.rate = rate;
+        self.max_frames = max_frames;
+        self.warmup_left = self.warmup;
+        self.non_finite = 0;
+
+        self.period_frames = match self.max_fps {
+            Some(fps) => (rate as f64 / fps).ceil() as usize,
+            None => 0,
+        };
+        self.pending.clear();
+        let most_frames = if self.period_frames > 0 {
+            self.pending
+                .reserve((self.period_frames + max_frames) * n_channels);
+            self.period_frames + max_frames
+        } else {
+            max_frames
+        };
+        self.processor.reserve(most_frames);
+        Ok(())
+    }
+
+    /// Start over as on a new stream of the same format, e.g. for another
+    /// pass over a file.
+    pub fn restart(&mut self) -> Result<(), ConfigError> {
+        self.configure(self.n_channels, self.rate, self.max_frames)
+    }
+
+    pub fn processor(&self) -> &AudioProcessor {
+        &self.processor
+    }
+
+    /// Stage timings since the last reset, `None` unless
+    /// [`AnalyzerConfig::profile`] is set. Decoding happens before the
+    /// analyzer sees a buffer, so the caller accounts for it here too.
+    pub fn profile(&mut self) -> Option<&mut Profile> {
+        self.profile.as_mut()
+    }
+
+    pub fn recorder(&self) -> Option<&WavRecorder> {
+        self.recorder.as_ref()
+    }
+
+    /// Record the input from the next push on, or stop recording with
+    /// `None`, which finishes the file. The recorder has to match the
+    /// configured format.
+    pub fn set_recorder(&mut self, recorder: Option<WavRecorder>) {
+        self.recorder = recorder;
+    }
+
+    /// Whether [`AnalyzerConfig::max_frames`] have been analyzed.
+    pub fn is_finished(&self) -> bool {
+        self.frames_left == Some(0)
+    }
+
+    /// Analyze the whole frames of interleaved `samples`, which may be
+    /// changed in place, and call `report` with the results. That happens
+    /// once per buffer, or once per period with `max_fps`, plus once more
+    /// for a buffer the warm-up ends partway through.
+    pub fn push(&mut self, mut samples: &mut [f32], mut report: impl FnMut(Analysis<'_>)) {
+        let n_channels = self.n_channels;
+        if n_channels == 0 {
+            return;
+        }
+        if self.warmup_left > 0 {
+            // split where the warm-up ends, so reporting and max_frames both
+            // start on the next frame
+            let n_frames = (samples.len() / n_channels).min(self.warmup_left as usize);
+            self.warmup_left -= n_frames as u64;
+            let (warmup, rest) = samples.split_at_mut(n_frames * n_channels);
+            self.non_finite += sanitize(warmup);
+            self.processor.count_clipped(warmup);
+            if let Some(recorder) = &mut self.recorder {
+                recorder.push(warmup);
+            }
+            self.analyze(warmup, true, &mut report);
+            samples = rest;
+            if samples.len() < n_channels {
+                return;
+            }
+        }
+
+        let mut n_frames = samples.len() / n_channels;
+        if let Some(left) = &mut self.frames_left {
+            if *left == 0 {
+                return;
+            }
+            // stop at exactly max_frames, partway through a buffer if need be
+            n_frames = n_frames.min(*left as usize);
+            *left -= n_frames as u64;
+        }
+        let samples = &mut samples[..n_frames * n_channels];
+        self.non_finite += sanitize(samples);
+        // before anything conditions them, so the recording and the clip
+        // counts are of the input
+        self.processor.count_clipped(samples);
+        if let Some(recorder) = &mut self.recorder {
+            recorder.push(samples);
+        }
+
+        if self.period_frames > 0 {
+            self.pending.extend_from_slice(samples);
+            if self.pending.len() < self.period_frames * n_channels && !self.is_finished() {
+                return;
+            }
+            // taken out for the analysis and put back after, neither allocates
+            let mut pending = mem::take(&mut self.pending);
+            self.analyze(&mut pending, false, &mut report);
+            pending.clear();
+            self.pending = pending;
+        } else {
+            self.analyze(samples, false, &mut report);
+        }
+    }
+
+    fn analyze(
+        &mut self,
+        samples: &mut [f32],
+        warmup: bool,
+        report: &mut impl FnMut(Analysis<'_>),
+    ) {
+        let (n_channels, rate) = (self.n_channels, self.rate);
+        let timing = self.profile.is_some();
+
+        let started = timing.then(Instant::now);
+        self.filters.process(samples);
+        time(&mut self.profile, Stage::Filter, started);
+        let started = timing.then(Instant::now);
+        self.preemphasis.process(samples);
+        time(&mut self.profile, Stage::Preemphasis, started);
+        let started = timing.then(Instant::now);
+        if let Some(agc) = &mut self.agc {
+            agc.process(samples, n_channels, rate);
+        }
+        time(&mut self.profile, Stage::Agc, started);
+
+        let started = timing.then(Instant::now);
+        self.processor.process_frame(samples, n_channels, rate);
+        time(&mut self.profile, Stage::Analysis, started);
+        if let Some(profile) = &mut self.profile {
+            profile.buffers += 1;
+        }
+
+        report(Analysis {
+            samples,
+            n_channels,
+            rate,
+            levels: self.processor.levels(),
+            processor: &self.processor,
+            agc_gain: self.agc.as_ref().map(AutoGain::gain),
+            non_finite: mem::take(&mut self.non_finite),
+            warmup,
+        });
+    }
+}
+
+/// Account the time since `started` to `stage`, if profiling.
+fn time(profile: &mut Option<Profile>, stage: Stage, started: Option<Instant>) {
+    if let (Some(profile), Some(started)) = (profile, started) {
+        profile.add(stage, started.elapsed());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processor::ChannelGains;
+
+    const RATE: u32 = 48000;
+
+    /// Push `buffers` of `n_frames` of mono samples at 0.5 and return how
+    /// many frames each report covered, and whether it was warm-up.
+    fn run(config: AnalyzerConfig, n_frames: usize, buffers: usize) -> Vec<(usize, bool)> {
+        let mut analyzer = Analyzer::new(config);
+        analyzer.configure(1, RATE, n_frames).unwrap();
+        let mut reports = Vec::new();
+        for _ in 0..buffers {
+            let mut samples = vec![0.5; n_frames];
+            analyzer.push(&mut samples, |analysis| {
+                reports.push((analysis.samples.len(), analysis.warmup))
+            });
+        }
+        reports
+    }
+
+    #[test]
+    fn reports_every_buffer() {
+        let reports = run(AnalyzerConfig::default(), 256, 3);
+        assert_eq!(reports, [(256, false); 3]);
+    }
+
+    #[test]
+    fn warms_up_first() {
+        let config = AnalyzerConfig {
+            warmup: 512,
+            ..Default::default()
+        };
+        let reports = run(config, 256, 3);
+        assert_eq!(reports, [(256, true), (256, true), (256, false)]);
+    }
+
+    #[test]
+    fn warmup_ends_partway_through_a_buffer() {
+        let config = AnalyzerConfig {
+            warmup: 300,
+            max_frames: Some(256),
+            ..Default::default()
+        };
+        let reports = run(config, 256, 4);
+        assert_eq!(
+            reports,
+            [(256, true), (44, true), (212, false), (44, false)]
+        );
+    }
+
+    #[test]
+    fn warmup_doesnt_count_towards_max_frames() {
+        let config = AnalyzerConfig {
+            warmup: 1,
+            max_frames: Some(1024),
+            ..Default::default()
+        };
+        let reports = run(config, 1024, 2);
+        assert_eq!(reports, [(1, true), (1023, false), (1, false)]);
+    }
+
+    #[test]
+    fn stops_at_max_frames() {
+        let config = AnalyzerConfig {
+            max_frames: Some(600),
+            ..Default::default()
+        };
+        let reports = run(config, 256, 4);
+        assert_eq!(reports, [(256, false), (256, false), (88, false)]);
+    }
+
+    #[test]
+    fn gathers_buffers_with_max_fps() {
+        let config = AnalyzerConfig {
+            // 1000 frames a period
+            max_fps: Some(48.0),
+            ..Default::default()
+        };
+        let reports = run(config, 256, 8);
+        assert_eq!(reports, [(1024, false), (1024, false)]);
+    }
+
+    #[test]
+    fn takes_buffers_of_any_size() {
+        let mut analyzer = Analyzer::new(AnalyzerConfig {
+            max_fps: Some(100.0),
+            ..Default::default()
+        });
+        analyzer.configure(2, RATE, 256).unwrap();
+        let mut frames = 0;
+        // a ragged last frame is left out, anything longer than configured
+        // for is still analyzed
+        for n_samples in [0, 1, 3, 512, 4096, 7] {
+            let mut samples = vec![0.25; n_samples];
+            analyzer.push(&mut samples, |analysis| {
+                assert_eq!(analysis.samples.len() % 2, 0);
+                frames += analysis.samples.len() / 2;
+            });
+        }
+        // every whole frame so far, once a period of 480 is gathered; the
+        // last 3 wait for the next period
+        assert_eq!(frames, 1 + 256 + 2048);
+    }
+
+    #[test]
+    fn replaces_non_finite_samples() {
+        let mut analyzer = Analyzer::new(AnalyzerConfig {
+            processor: ProcessorConfig {
+                remove_dc: false,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        analyzer.configure(2, RATE, 2).unwrap();
+        let mut samples = [f32::NAN, 0.5, 0.5, f32::INFINITY];
+        analyzer.push(&mut samples, |analysis| {
+            assert_eq!(analysis.non_finite, 2);
+            assert_eq!(analysis.levels[0].peak, 0.5);
+            assert_eq!(analysis.levels[1].peak, 0.5);
+        });
+    }
+
+    #[test]
+    fn records_the_input_before_any_conditioning() {
+        let path = std::env::temp_dir().join(format!("analyzer-record-{}.wav", std::process::id()));
+        let mut analyzer = Analyzer::new(AnalyzerConfig {
+            processor: ProcessorConfig {
+                channel_gains: ChannelGains::parse_list("2.0").unwrap(),
+                ..Default::default()
+            },
+            filters: vec![FilterSpec::HighPass(1000.0)],
+            preemphasis: 0.97,
+            agc: Some(AutoGain::new(-6.0, 0.01, 0.1, 30.0)),
+            warmup: 100,
+            ..Default::default()
+        });
+        analyzer.configure(1, RATE, 256).unwrap();
+        analyzer.set_recorder(Some(WavRecorder::create(&path, 1, RATE).unwrap()));
+        let mut input: Vec<f32> = (0..512).map(|i| (i as f32 * 0.01).sin() * 0.25).collect();
+        input[300] = f32::NAN;
+        for chunk in input.chunks(256) {
+            analyzer.push(&mut chunk.to_vec(), |_| {});
+        }
+        // dropping the recorder finishes the file
+        analyzer.set_recorder(None);
+
+        let recorded: Vec<f32> = hound::WavReader::open(&path)
+            .unwrap()
+            .into_samples()
+            .map(Result::unwrap)
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        input[300] = 0.0;
+        assert_eq!(recorded, input);
+    }
+
+    #[test]
+    fn counts_clipping_in_the_input() {
+        let mut analyzer = Analyzer::new(AnalyzerConfig {
+            processor: ProcessorConfig {
+                remove_dc: false,
+                ..Default::default()
+            },
+            // pulls a quiet input well past full scale
+            agc: Some(AutoGain::new(6.0, 0.001, 0.001, 30.0)),
+            ..Default::default()
+        });
+        analyzer.configure(1, RATE, 256).unwrap();
+        let mut clipped = Vec::new();
+        for amplitude in [0.1, 0.1, 1.0] {
+            let mut samples = vec![amplitude; 256];
+            analyzer.push(&mut samples, |analysis| {
+                clipped.push((analysis.levels[0].peak > 1.0, analysis.levels[0].clipped))
+            });
+        }
+        assert_eq!(clipped[1], (true, 0));
+        assert_eq!(clipped[2].1, 256);
+    }
+}
@@ -0,0 +1,1079 @@
+//! Capturing from PipeWire, the default `--backend`, and asking the server
+//! about its nodes.
+//!
+//! Each stream's `process` callback decodes and analyzes its buffers on the
+//! real-time data thread, and reports the results to the main loop, which
+//! prints them.
+
+use crate::{
+    Error, Event, MAX_CHANNELS, Opt, Outputs, PRINT_INTERVAL, Scheduling, UserData, channel_name,
+};
+use pipewire as pw;
+use pw::{loop_::Signal, properties::properties, spa};
+use rust_audio_monitor::{SampleFormat, Stage};
+use spa::param::audio::AudioFormat;
+use spa::param::format::{MediaSubtype, MediaType};
+use spa::param::format_utils;
+use spa::pod::Pod;
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+// the channel arrays are sized for every position a format can carry
+const _: () = assert!(MAX_CHANNELS == spa::param::audio::MAX_CHANNELS);
+
+/// Most frames of one buffer the data thread will decode, PipeWire's
+/// default `clock.max-quantum`. Anything past it is dropped.
+const MAX_FRAMES: usize = 8192;
+
+/// Rate `--quantum` is given at, PipeWire's default graph rate.
+const QUANTUM_RATE: u32 = 48000;
+
+/// Wait before the first reconnect after a stream error. Each further
+/// attempt waits twice as long, up to `RECONNECT_DOUBLINGS` times.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_DOUBLINGS: u32 = 5;
+
+/// Where a stream's `UserData` waits between connections.
+type Home = Arc<Mutex<Option<UserData>>>;
+
+/// A capture stream's `UserData` for one connection, and what its callbacks
+/// keep track of about the negotiated format.
+///
+/// The stream listener owns it, so the main loop and data thread callbacks
+/// are handed it without sharing or borrowing anything. Dropping the
+/// listener, after the stream is disconnected, puts `data` back in `home`
+/// for the next connection.
+struct StreamData {
+    /// `None` only once handed back.
+    data: Option<UserData>,
+    home: Home,
+    format: spa::param::audio::AudioInfoRaw,
+    /// Sample encoding of `format`, `None` until a format we can decode is negotiated.
+    sample_format: Option<SampleFormat>,
+    /// Set once the data thread priority has been checked from the first
+    /// `process` call of a connection.
+    priority_checked: bool,
+    /// Frames in the last buffer `process` reported, to notice the
+    /// quantum changing.
+    quantum: usize,
+    /// Set when a format is negotiated, for `process` to report its
+    /// channel positions.
+    channel_map_changed: bool,
+}
+
+impl StreamData {
+    /// Take the user data out of `home` for a new connection, with nothing
+    /// negotiated yet.
+    fn lend(home: &Home) -> StreamData {
+        StreamData {
+            data: home.lock().ok().and_then(|mut data| data.take()),
+            home: home.clone(),
+            format: Default::default(),
+            sample_format: None,
+            priority_checked: false,
+            quantum: 0,
+            channel_map_changed: false,
+        }
+    }
+}
+
+impl Drop for StreamData {
+    fn drop(&mut self) {
+        if let Ok(mut home) = self.home.lock() {
+            *home = self.data.take();
+        }
+    }
+}
+
+/// Capture into `streams`, one per `--target`, until Ctrl-C, `--run-for`
+/// or the streams are done, printing to `outputs` from the main loop.
+pub fn run(opt: &Opt, streams: Vec<UserData>, outputs: Rc<Outputs>) -> Result<(), Error> {
+    pw::init();
+
+    let mainloop = pw::main_loop::MainLoopRc::new(None)?;
+
+    /* Quit the main loop on Ctrl-C or SIGTERM, so the stream gets
+     * disconnected cleanly instead of the process dying mid-buffer. */
+    let mainloop_weak = mainloop.downgrade();
+    let _sig_int = mainloop.loop_().add_signal_local(Signal::SIGINT, move || {
+        if let Some(mainloop) = mainloop_weak.upgrade() {
+            mainloop.quit();
+        }
+    });
+    let mainloop_weak = mainloop.downgrade();
+    let _sig_term = mainloop.loop_().add_signal_local(Signal::SIGTERM, move || {
+        if let Some(mainloop) = mainloop_weak.upgrade() {
+            mainloop.quit();
+        }
+    });
+
+    let mainloop_weak = mainloop.downgrade();
+    let _print_timer = if !outputs.printers.is_empty() || opt.max_frames.is_some() {
+        let timer = mainloop.loop_().add_timer(move |_| {
+            // check before draining, so whatever led up to it gets printed
+            let finished = outputs.finished();
+            outputs.print();
+            if (finished || outputs.dumped())
+                && let Some(mainloop) = mainloop_weak.upgrade()
+            {
+                mainloop.quit();
+            }
+        });
+        timer
+            .update_timer(Some(PRINT_INTERVAL), Some(PRINT_INTERVAL))
+            .into_sync_result()?;
+        Some(timer)
+    } else {
+        None
+    };
+
+    let mainloop_weak = mainloop.downgrade();
+    let _deadline = match opt.deadline() {
+        Some(delay) => {
+            let timer = mainloop.loop_().add_timer(move |_| {
+                if let Some(mainloop) = mainloop_weak.upgrade() {
+                    mainloop.quit();
+                }
+            });
+            timer.update_timer(Some(delay), None).into_sync_result()?;
+            Some(timer)
+        }
+        None => None,
+    };
+
+    capture(&mainloop, opt, streams)
+}
+
+/// A node announced on the registry.
+#[derive(Clone, Debug)]
+struct NodeInfo {
+    id: u32,
+    serial: Option<String>,
+    name: Option<String>,
+    description: Option<String>,
+    media_class: Option<String>,
+}
+
+impl NodeInfo {
+    /// Whether `target`, as given to `--target`, names this node. Numeric
+    /// targets match the object id or serial, anything else the node name.
+    fn matches(&self, target: &str) -> bool {
+        match target.parse::<u32>() {
+            Ok(id) => self.id == id || self.serial.as_deref() == Some(target),
+            Err(_) => self.name.as_deref() == Some(target),
+        }
+    }
+
+    /// Whether this node produces or consumes audio, as opposed to video,
+    /// MIDI or a device without streams of its own.
+    fn is_audio(&self) -> bool {
+        self.media_class
+            .as_deref()
+            .is_some_and(|class| class.starts_with("Audio/"))
+    }
+}
+
+impl std::fmt::Display for NodeInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} ({})", self.id, name),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+/// A link between two nodes announced on the registry.
+struct LinkInfo {
+    id: u32,
+    output_node: u32,
+    input_node: u32,
+}
+
+/// What the registry has told us about the graph so far.
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<NodeInfo>,
+    links: Vec<LinkInfo>,
+}
+
+impl Graph {
+    /// The node feeding `node_id`, if a link into it has been announced.
+    fn source_of(&self, node_id: u32) -> Option<&NodeInfo> {
+        let link = self.links.iter().find(|l| l.input_node == node_id)?;
+        self.nodes.iter().find(|n| n.id == link.output_node)
+    }
+}
+
+/// Keeps a [`Graph`] in step with the registry for as long as it lives.
+struct GraphWatch {
+    graph: Rc<RefCell<Graph>>,
+    _listener: pw::registry::Listener,
+    registry: pw::registry::RegistryRc,
+}
+
+impl GraphWatch {
+    fn new(core: &pw::core::CoreRc) -> Result<GraphWatch, pw::Error> {
+        let graph = Rc::new(RefCell::new(Graph::default()));
+        let registry = core.get_registry_rc()?;
+        let graph_clone = graph.clone();
+        let graph_remove = graph.clone();
+        let listener = registry
+            .add_listener_local()
+            .global(move |global| {
+                let Some(props) = global.props else {
+                    return;
+                };
+                let mut graph = graph_clone.borrow_mut();
+                let prop = |key: &str| props.get(key).map(str::to_owned);
+                match global.type_ {
+                    pw::types::ObjectType::Node => graph.nodes.push(NodeInfo {
+                        id: global.id,
+                        serial: prop(*pw::keys::OBJECT_SERIAL),
+                        name: prop(*pw::keys::NODE_NAME),
+                        description: prop(*pw::keys::NODE_DESCRIPTION),
+                        media_class: prop(*pw::keys::MEDIA_CLASS),
+                    }),
+                    pw::types::ObjectType::Link => {
+                        let node = |key: &str| -> Option<u32> { props.get(key)?.parse().ok() };
+                        if let (Some(output_node), Some(input_node)) = (
+                            node(*pw::keys::LINK_OUTPUT_NODE),
+                            node(*pw::keys::LINK_INPUT_NODE),
+                        ) {
+                            graph.links.push(LinkInfo {
+                                id: global.id,
+                                output_node,
+                                input_node,
+                            });
+                        }
+                    }
+                    _ => {}
+                }
+            })
+            .global_remove(move |id| {
+                let mut graph = graph_remove.borrow_mut();
+                graph.nodes.retain(|n| n.id != id);
+                graph.links.retain(|l| l.id != id);
+            })
+            .register();
+
+        Ok(GraphWatch {
+            graph,
+            _listener: listener,
+            registry,
+        })
+    }
+}
+
+/// Run the main loop until the server has answered a sync, so every global
+/// that existed before the call has been announced on the registry.
+/// Returns `false` if something else quit it first, e.g. Ctrl-C.
+fn roundtrip(
+    mainloop: &pw::main_loop::MainLoopRc,
+    core: &pw::core::CoreRc,
+) -> Result<bool, pw::Error> {
+    let done = Rc::new(Cell::new(false));
+    let done_clone = done.clone();
+    let loop_clone = mainloop.clone();
+    // a core error, such as the server going away, means no done ever comes
+    let failed = Rc::new(Cell::new(0));
+    let failed_clone = failed.clone();
+    let loop_error = mainloop.clone();
+
+    let pending = core.sync(0)?;
+    let _listener_core = core
+        .add_listener_local()
+        .done(move |id, seq| {
+            if id == pw::core::PW_ID_CORE && seq == pending {
+                done_clone.set(true);
+                loop_clone.quit();
+            }
+        })
+        .error(move |id, _, res, message| {
+            if id == pw::core::PW_ID_CORE {
+                error!("PipeWire error: {}", message);
+                failed_clone.set(res.min(-1));
+                loop_error.quit();
+            }
+        })
+        .register();
+
+    mainloop.run();
+    if failed.get() != 0 {
+        spa::utils::result::SpaResult::from_c(failed.get()).into_sync_result()?;
+    }
+    Ok(done.get())
+}
+
+/// Best-effort priority boost for the calling thread.
+///
+/// PipeWire normally promotes its data thread to SCHED_FIFO through rtkit
+/// or RLIMIT_RTPRIO. When that didn't happen the thread is still
+/// SCHED_OTHER, so fall back to lowering its nice value, which needs no
+/// special privileges up to RLIMIT_NICE.
+fn raise_thread_priority() -> Scheduling {
+    const NICE_LEVEL: libc::c_int = -11;
+
+    unsafe {
+        let mut policy = 0;
+        let mut param: libc::sched_param = mem::zeroed();
+        if libc::pthread_getschedparam(libc::pthread_self(), &mut policy, &mut param) == 0 {
+            match policy {
+                libc::SCHED_FIFO => return Scheduling::Fifo(param.sched_priority),
+                libc::SCHED_RR => return Scheduling::RoundRobin(param.sched_priority),
+                _ => {}
+            }
+        }
+
+        // On Linux the nice value is per thread, so target our own tid
+        // rather than the whole process.
+        #[cfg(target_os = "linux")]
+        let who = libc::gettid() as libc::id_t;
+        #[cfg(not(target_os = "linux"))]
+        let who = 0;
+
+        if libc::setpriority(libc::PRIO_PROCESS, who, NICE_LEVEL) == 0 {
+            Scheduling::Other {
+                nice: NICE_LEVEL,
+                error: None,
+            }
+        } else {
+            let error = std::io::Error::last_os_error().raw_os_error();
+            Scheduling::Other {
+                nice: libc::getpriority(libc::PRIO_PROCESS, who),
+                error,
+            }
+        }
+    }
+}
+
+/// Formats offered when connecting, in order of preference.
+const NEGOTIATED_FORMATS: [AudioFormat; 3] =
+    [AudioFormat::F32LE, AudioFormat::S32LE, AudioFormat::S16LE];
+
+/// Map a negotiated format onto its sample encoding. Planar variants decode
+/// the same way, they only differ in how channels are laid out.
+fn sample_format(format: AudioFormat) -> Option<SampleFormat> {
+    match format {
+        AudioFormat::F32LE | AudioFormat::F32P => Some(SampleFormat::F32),
+        AudioFormat::S32LE | AudioFormat::S32P => Some(SampleFormat::S32),
+        AudioFormat::S16LE | AudioFormat::S16P => Some(SampleFormat::S16),
+        _ => None,
+    }
+}
+
+/// Log the full negotiated format, for `--verbose-format`. `quantum` is the
+/// `--quantum` asked for, if any.
+fn log_format(format: &spa::param::audio::AudioInfoRaw, quantum: Option<u32>) {
+    let n_channels = (format.channels() as usize).min(spa::param::audio::MAX_CHANNELS);
+    info!(
+        "format: {:?}, {} bytes per sample",
+        format.format(),
+        sample_format(format.format()).map_or(0, SampleFormat::size)
+    );
+    info!("rate: {} Hz", format.rate());
+    info!("channels: {}", format.channels());
+    info!("flags: {:?}", format.flags());
+    if format
+        .flags()
+        .contains(spa::param::audio::AudioInfoRawFlags::UNPOSITIONED)
+    {
+        info!("positions: unpositioned");
+    } else {
+        info!("positions: {:?}", &format.position()[..n_channels]);
+    }
+    match quantum {
+        Some(quantum) => info!(
+            "quantum asked for: {} frames at {} Hz",
+            quantum, QUANTUM_RATE
+        ),
+        None => info!("quantum asked for: graph default"),
+    }
+    // buffers are sized by the graph; the first one is reported as the quantum
+}
+
+/// Print the audio nodes currently in the graph.
+pub fn list_devices(json: bool) -> Result<(), Error> {
+    pw::init();
+    let mainloop = pw::main_loop::MainLoopRc::new(None)?;
+    let context = pw::context::ContextRc::new(&mainloop, None)?;
+    let core = context.connect_rc(None)?;
+    let watch = GraphWatch::new(&core)?;
+    roundtrip(&mainloop, &core)?;
+
+    let graph = watch.graph.borrow();
+    let mut nodes: Vec<&NodeInfo> = graph.nodes.iter().filter(|n| n.is_audio()).collect();
+    nodes.sort_by_key(|n| n.id);
+    for node in nodes {
+        if json {
+            let line = serde_json::json!({
+                "id": node.id,
+                "serial": node.serial,
+                "name": node.name,
+                "description": node.description,
+                "media_class": node.media_class,
+            });
+            println!("{}", line);
+        } else {
+            println!(
+                "{:>5}  {:<22}  {}  [{}]",
+                node.id,
+                node.media_class.as_deref().unwrap_or("-"),
+                node.name.as_deref().unwrap_or("-"),
+                node.description.as_deref().unwrap_or("-"),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Print the raw audio formats each of `targets` offers, as its EnumFormat
+/// params describe them, without connecting a stream.
+pub fn list_formats(targets: &[String]) -> Result<(), Error> {
+    pw::init();
+    let mainloop = pw::main_loop::MainLoopRc::new(None)?;
+    let context = pw::context::ContextRc::new(&mainloop, None)?;
+    let core = context.connect_rc(None)?;
+    let watch = GraphWatch::new(&core)?;
+    roundtrip(&mainloop, &core)?;
+
+    for target in targets {
+        let node = watch
+            .graph
+            .borrow()
+            .nodes
+            .iter()
+            .find(|n| n.matches(target))
+            .cloned();
+        let Some(node) = node else {
+            return Err(Error::TargetNotFound(target.clone()));
+        };
+
+        // binding only needs the id and type of the global
+        let global = pw::registry::GlobalObject {
+            id: node.id,
+            permissions: pw::permissions::PermissionFlags::empty(),
+            type_: pw::types::ObjectType::Node,
+            version: 0,
+            props: None::<&spa::utils::dict::DictRef>,
+        };
+        let proxy: pw::node::Node = watch.registry.bind(&global)?;
+        let formats = Rc::new(RefCell::new(Vec::new()));
+        let formats_param = formats.clone();
+        let _listener = proxy
+            .add_listener_local()
+            .param(move |_, id, _, _, param| {
+                if id != pw::spa::param::ParamType::EnumFormat {
+                    return;
+                }
+                if let Some(format) = param.and_then(describe_format) {
+                    formats_param.borrow_mut().push(format);
+                }
+            })
+            .register();
+        proxy.enum_params(0, Some(pw::spa::param::ParamType::EnumFormat), 0, u32::MAX);
+        // the params arrive before the server answers the sync
+        roundtrip(&mainloop, &core)?;
+
+        println!("{}", node);
+        let formats = formats.borrow();
+        if formats.is_empty() {
+            println!("  no raw audio formats");
+        }
+        for format in formats.iter() {
+            println!("  {}", format);
+        }
+    }
+    Ok(())
+}
+
+/// One line describing an EnumFormat param, e.g.
+/// `S16LE,S32LE rate:44100-192000 channels:2 positions:FL,FR`. `None`
+/// unless the param is raw audio.
+fn describe_format(param: &Pod) -> Option<String> {
+    let (media_type, media_subtype) = format_utils::parse_format(param).ok()?;
+    if media_type != MediaType::Audio || media_subtype != MediaSubtype::Raw {
+        return None;
+    }
+    let (_, value) =
+        spa::pod::deserialize::PodDeserializer::deserialize_any_from(param.as_bytes()).ok()?;
+    let spa::pod::Value::Object(object) = value else {
+        return None;
+    };
+
+    let mut parts = Vec::new();
+    for property in &object.properties {
+        let (name, value) = match property.key {
+            spa::sys::SPA_FORMAT_AUDIO_format => ("", describe_ids(&property.value, format_name)),
+            spa::sys::SPA_FORMAT_AUDIO_rate => ("rate:", describe_ints(&property.value)),
+            spa::sys::SPA_FORMAT_AUDIO_channels => ("channels:", describe_ints(&property.value)),
+            spa::sys::SPA_FORMAT_AUDIO_position => match &property.value {
+                spa::pod::Value::ValueArray(spa::pod::ValueArray::Id(positions)) => {
+                    let names: Vec<String> = positions
+                        .iter()
+                        .map(|&spa::utils::Id(position)| channel_name(position))
+                        .collect();
+                    ("positions:", Some(names.join(",")))
+                }
+                _ => continue,
+            },
+            _ => continue,
+        };
+        if let Some(value) = value {
+            parts.push(format!("{}{}", name, value));
+        }
+    }
+    Some(parts.join(" "))
+}
+
+/// `F32LE` rather than `AudioFormat::F32LE`.
+fn format_name(raw: u32) -> String {
+    let name = format!("{:?}", AudioFormat::from_raw(raw));
+    name.trim_start_matches("AudioFormat::").to_owned()
+}
+
+/// An id property, one value or a choice of them.
+fn describe_ids(value: &spa::pod::Value, show: impl Fn(u32) -> String) -> Option<String> {
+    match value {
+        spa::pod::Value::Id(spa::utils::Id(id)) => Some(show(*id)),
+        spa::pod::Value::Choice(spa::pod::ChoiceValue::Id(spa::utils::Choice(_, choice))) => {
+            Some(describe_choice(choice, |spa::utils::Id(id)| show(id)))
+        }
+        _ => None,
+    }
+}
+
+/// An integer property, one value or a choice of them.
+fn describe_ints(value: &spa::pod::Value) -> Option<String> {
+    match value {
+        spa::pod::Value::Int(value) => Some(value.to_string()),
+        spa::pod::Value::Choice(spa::pod::ChoiceValue::Int(spa::utils::Choice(_, choice))) => {
+            Some(describe_choice(choice, |value| value.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// A range as `min-max`, a list as `a,b,c`.
+fn describe_choice<T>(choice: &spa::utils::ChoiceEnum<T>, show: impl Fn(T) -> String) -> String
+where
+    T: spa::pod::CanonicalFixedSizedPod + Copy + PartialEq,
+{
+    match choice {
+        spa::utils::ChoiceEnum::None(value) => show(*value),
+        spa::utils::ChoiceEnum::Range { min, max, .. }
+        | spa::utils::ChoiceEnum::Step { min, max, .. } => {
+            format!("{}-{}", show(*min), show(*max))
+        }
+        spa::utils::ChoiceEnum::Enum {
+            default,
+            alternatives: values,
+        }
+        | spa::utils::ChoiceEnum::Flags {
+            default,
+            flags: values,
+        } => {
+            // the default usually comes again among the alternatives
+            let mut distinct = vec![*default];
+            for value in values {
+                if !distinct.contains(value) {
+                    distinct.push(*value);
+                }
+            }
+            let values: Vec<String> = distinct.into_iter().map(show).collect();
+            values.join(",")
+        }
+    }
+}
+
+fn capture(
+    mainloop: &pw::main_loop::MainLoopRc,
+    opt: &Opt,
+    streams: Vec<UserData>,
+) -> Result<(), Error> {
+    /* Make one parameter per supported format. The SPA_PARAM_EnumFormat
+     * id means that this is a format enumeration, and the server picks the
+     * first one the node can provide, so they are listed in order of
+     * preference. We leave the channels and rate empty to accept the
+     * native graph rate and channels. */
+    let params: Vec<Vec<u8>> = NEGOTIATED_FORMATS
+        .iter()
+        .map(|&format| {
+            let mut audio_info = spa::param::audio::AudioInfoRaw::new();
+            audio_info.set_format(format);
+            let obj = pw::spa::pod::Object {
+                type_: pw::spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
+                id: pw::spa::param::ParamType::EnumFormat.as_raw(),
+                properties: audio_info.into(),
+            };
+            let (cursor, _) = pw::spa::pod::serialize::PodSerializer::serialize(
+                std::io::Cursor::new(Vec::new()),
+                &pw::spa::pod::Value::Object(obj),
+            )
+            .map_err(|err| Error::Params(err.to_string()))?;
+            Ok(cursor.into_inner())
+        })
+        .collect::<Result<_, Error>>()?;
+
+    /* The user data outlives each connection, so the processors and
+     * recordings carry on where they left off when the streams are
+     * recreated. */
+    let streams: Vec<Home> = streams
+        .into_iter()
+        .map(|data| Arc::new(Mutex::new(Some(data))))
+        .collect();
+    let status = Rc::new(StreamStatus::default());
+
+    let mut retries = 0;
+    loop {
+        /* Anything but an error on a stream or the connection, such as
+         * Ctrl-C or --run-for, ends the capture. An error reconnects
+         * everything from a new context, so a restarted server is picked
+         * up again. Only the first connection has to succeed outright. */
+        match connect(mainloop, opt, &streams, &params, &status) {
+            Ok(()) => {}
+            Err(err) if !status.connected.get() => return Err(err),
+            Err(err) => {
+                error!("{}", err);
+                status.failed.set(true);
+            }
+        }
+        if let Some(err) = status.error.take() {
+            return Err(err);
+        }
+        if !status.failed.replace(false) {
+            return Ok(());
+        }
+        if status.streamed.replace(false) {
+            retries = 0;
+        }
+        if retries == opt.max_retries {
+            return Err(Error::StreamFailed { retries });
+        }
+        retries += 1;
+        let delay = RECONNECT_DELAY * 2u32.pow((retries - 1).min(RECONNECT_DOUBLINGS));
+        warn!(
+            "reconnecting in {:?}, attempt {} of {}",
+            delay, retries, opt.max_retries
+        );
+        if !wait(mainloop, delay)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Connect to the server with a new context, capture into `streams` until
+/// the main loop quits, and disconnect again.
+fn connect(
+    mainloop: &pw::main_loop::MainLoopRc,
+    opt: &Opt,
+    streams: &[Home],
+    params: &[Vec<u8>],
+    status: &Rc<StreamStatus>,
+) -> Result<(), Error> {
+    let context = pw::context::ContextRc::new(mainloop, None)?;
+    let core = context.connect_rc(None)?;
+
+    // the server going away ends the connection like a stream error
+    let (mainloop_error, status_error) = (mainloop.downgrade(), status.clone());
+    let _core_listener = core
+        .add_listener_local()
+        .error(move |id, _, _, message| {
+            if id == pw::core::PW_ID_CORE {
+                error!("PipeWire error: {}", message);
+                status_error.failed.set(true);
+                if let Some(mainloop) = mainloop_error.upgrade() {
+                    mainloop.quit();
+                }
+            }
+        })
+        .register();
+
+    /* Keep track of nodes and links for the lifetime of the streams, so we
+     * can resolve --target up front and tell which node each stream ended
+     * up linked to once the format is negotiated. */
+    let watch = GraphWatch::new(&core)?;
+    let graph = watch.graph.clone();
+
+    let targets: Vec<Option<NodeInfo>> = if opt.target.is_empty() {
+        vec![None]
+    } else {
+        // stopped before the graph was known, so there is nothing to capture
+        if !roundtrip(mainloop, &core)? {
+            return Ok(());
+        }
+        opt.target
+            .iter()
+            .map(|target| {
+                let node = graph
+                    .borrow()
+                    .nodes
+                    .iter()
+                    .find(|n| n.matches(target))
+                    .cloned();
+                node.map(Some)
+                    .ok_or_else(|| Error::TargetNotFound(target.clone()))
+            })
+            .collect::<Result<_, _>>()?
+    };
+
+    let captures = targets
+        .iter()
+        .zip(streams)
+        .map(|(target, home)| {
+            // everything but the user data starts over, the data thread
+            // priority check included
+            capture_stream(
+                &core,
+                mainloop,
+                opt,
+                &graph,
+                status,
+                target.as_ref(),
+                StreamData::lend(home),
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut params: Vec<&Pod> = params
+        .iter()
+        .map(|values| {
+            Pod::from_bytes(values).ok_or_else(|| Error::Params(String::from("truncated pod")))
+        })
+        .collect::<Result<_, _>>()?;
+
+    /* Now connect the streams. We ask that our process function is
+     * called in a realtime thread. */
+    for capture in &captures {
+        capture.stream.connect(
+            spa::utils::Direction::Input,
+            capture.target_id,
+            pw::stream::StreamFlags::AUTOCONNECT
+                | pw::stream::StreamFlags::MAP_BUFFERS
+                | pw::stream::StreamFlags::RT_PROCESS,
+            &mut params,
+        )?;
+    }
+    status.connected.set(true);
+
+    // and wait while we let things run
+    mainloop.run();
+
+    for capture in &captures {
+        // fails once the server is gone, which is already being dealt with
+        if let Err(err) = capture.stream.disconnect() {
+            debug!("failed to disconnect: {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// One capture stream and the listener that owns its `StreamData`. The
+/// listener comes first so it is unhooked before the stream is destroyed.
+struct Capture<'c> {
+    _listener: pw::stream::StreamListener<StreamData>,
+    stream: pw::stream::StreamBox<'c>,
+    /// The node id to pass to `connect`, for servers without serials.
+    target_id: Option<u32>,
+}
+
+/// What the stream and core callbacks tell the reconnect loop.
+#[derive(Default)]
+struct StreamStatus {
+    /// Set when the main loop was stopped by a stream or core error, to
+    /// reconnect.
+    failed: Cell<bool>,
+    /// Set once the streams have been connected the first time, after
+    /// which failing to connect again is retried rather than fatal.
+    connected: Cell<bool>,
+    /// Set once a stream gets going, so retries only count failures in a
+    /// row.
+    streamed: Cell<bool>,
+    /// Set when a negotiated format can't be captured, which no reconnect
+    /// would change, to stop with it.
+    error: RefCell<Option<Error>>,
+}
+
+/// Create the stream capturing `target`, or whatever the session manager
+/// picks, into `data`.
+fn capture_stream<'c>(
+    core: &'c pw::core::CoreRc,
+    mainloop: &pw::main_loop::MainLoopRc,
+    opt: &Opt,
+    graph: &Rc<RefCell<Graph>>,
+    status: &Rc<StreamStatus>,
+    target: Option<&NodeInfo>,
+    data: StreamData,
+) -> Result<Capture<'c>, pw::Error> {
+    /* Create a simple stream, the simple stream manages the core and remote
+     * objects for you if you don't need to deal with them.
+     *
+     * If you plan to autoconnect your stream, you need to provide at least
+     * media, category and role properties.
+     *
+     * Pass your events and a user_data pointer as the last arguments. This
+     * will inform you about the stream state. The most important event
+     * you need to listen to is the process event where you need to produce
+     * the data.
+     */
+    let mut props = properties! {
+        *pw::keys::MEDIA_TYPE => "Audio",
+        *pw::keys::MEDIA_CATEGORY => "Capture",
+        *pw::keys::MEDIA_ROLE => "Music",
+    };
+
+    /* Link to the requested node by serial where the server provides one,
+     * and don't let the session manager move us to the default node if it
+     * goes away. Older servers only understand the node id passed to
+     * connect(). */
+    let mut target_id = None;
+    if let Some(node) = target {
+        match &node.serial {
+            Some(serial) => props.insert(*pw::keys::TARGET_OBJECT, serial.as_str()),
+            None => target_id = Some(node.id),
+        }
+        props.insert(*pw::keys::NODE_DONT_RECONNECT, "true");
+    }
+
+    // capture from the sink monitor ports, i.e. what is being played
+    if opt.monitor {
+        props.insert(*pw::keys::STREAM_CAPTURE_SINK, "true");
+    }
+
+    /* Ask for buffers of --quantum frames. The latency is a fraction of a
+     * second, so the server scales it if the graph runs at another rate,
+     * and it is only a hint: what we get is reported from `process`. */
+    if let Some(quantum) = opt.quantum {
+        let latency = format!("{}/{}", quantum, QUANTUM_RATE);
+        props.insert(*pw::keys::NODE_LATENCY, latency.as_str());
+    }
+
+    let stream = pw::stream::StreamBox::new(core, "audio-capture", props)?;
+
+    let mainloop_clone = mainloop.clone();
+    let mainloop_state = mainloop.clone();
+    let (verbose_format, quantum) = (opt.verbose_format, opt.quantum);
+    let (status_state, status_param) = (status.clone(), status.clone());
+    let graph = graph.clone();
+    let listener = stream
+        .add_local_listener_with_user_data(data)
+        .state_changed(move |_, _, old, new| {
+            if let pw::stream::StreamState::Error(err) = &new {
+                error!("stream error: {}", err);
+                status_state.failed.set(true);
+                mainloop_state.quit();
+            } else {
+                if new == pw::stream::StreamState::Streaming {
+                    status_state.streamed.set(true);
+                }
+                debug!("stream state: {:?} -> {:?}", old, new);
+            }
+        })
+        .param_changed(move |stream, state, id, param| {
+            let fail = |err| {
+                *status_param.error.borrow_mut() = Some(err);
+                mainloop_clone.quit();
+            };
+            // NULL means to clear the format
+            let Some(param) = param else {
+                return;
+            };
+            if id != pw::spa::param::ParamType::Format.as_raw() {
+                return;
+            }
+
+            let (media_type, media_subtype) = match format_utils::parse_format(param) {
+                Ok(v) => v,
+                Err(_) => return,
+            };
+
+            // only accept raw audio
+            if media_type != MediaType::Audio || media_subtype != MediaSubtype::Raw {
+                return;
+            }
+
+            // call a helper function to parse the format for us.
+            if let Err(err) = state.format.parse(param) {
+                fail(Error::ParseFormat(err));
+                return;
+            }
+
+            state.sample_format = sample_format(state.format.format());
+            if state.sample_format.is_none() {
+                fail(Error::UnsupportedFormat(format!(
+                    "sample format {:?}",
+                    state.format.format()
+                )));
+                return;
+            }
+
+            info!(
+                "capturing rate:{} channels:{} format:{:?}",
+                state.format.rate(),
+                state.format.channels(),
+                state.format.format()
+            );
+            match graph.borrow().source_of(stream.node_id()) {
+                Some(node) => info!("connected to node {}", node),
+                None => info!("connected as node {}", stream.node_id()),
+            }
+            if verbose_format {
+                log_format(&state.format, quantum);
+            }
+
+            let n_channels = state.format.channels() as usize;
+            if n_channels == 0 || n_channels > spa::param::audio::MAX_CHANNELS {
+                fail(Error::UnsupportedFormat(format!(
+                    "channel count {}",
+                    n_channels
+                )));
+                return;
+            }
+            // filter state from the old format belongs to other channels
+            let Some(data) = &mut state.data else {
+                return;
+            };
+            let rate = state.format.rate();
+            if let Err(err) = data.configure(n_channels, rate, MAX_FRAMES) {
+                fail(Error::Configure(err));
+                return;
+            }
+            state.channel_map_changed = true;
+            if let Err(err) = data.start_recording(n_channels, rate) {
+                fail(Error::Record(err));
+            }
+        })
+        .process(|stream, state| {
+            let Some(user_data) = &mut state.data else {
+                return;
+            };
+            if !state.priority_checked {
+                state.priority_checked = true;
+                let scheduling = raise_thread_priority();
+                if let Some(events) = &mut user_data.events {
+                    let _ = events.push(Event::Scheduling(scheduling));
+                }
+            }
+
+            match stream.dequeue_buffer() {
+                None => {
+                    if let Some(events) = &mut user_data.events {
+                        let _ = events.push(Event::OutOfBuffers);
+                    }
+                }
+                Some(mut buffer) => {
+                    let datas = buffer.datas_mut();
+                    if datas.is_empty() {
+                        return;
+                    }
+
+                    let Some(sample_format) = state.sample_format else {
+                        return;
+                    };
+                    let n_channels =
+                        (state.format.channels() as usize).min(spa::param::audio::MAX_CHANNELS);
+                    if n_channels == 0 {
+                        return;
+                    }
+                    let sample_size = sample_format.size();
+
+                    /* Interleaved formats such as the F32LE we ask for carry every
+                     * channel in a single data plane. Planar formats (F32P, S16P, ...)
+                     * get one plane per channel instead, and drivers may also
+                     * place the valid region anywhere in the mapped memory, so
+                     * honour each chunk's offset and size. */
+                    let planar = datas.len() > 1;
+                    let plane_channels = if planar { 1 } else { n_channels };
+                    let samples = &mut user_data.samples;
+                    let n_frames = datas
+                        .iter()
+                        .map(|data| data.chunk().size() as usize / sample_size / plane_channels)
+                        .max()
+                        .unwrap_or(0)
+                        .min(MAX_FRAMES);
+                    // an empty buffer has no levels; don't report it as silence
+                    if n_frames == 0 {
+                        return;
+                    }
+                    let started = Instant::now();
+                    samples[..n_frames * n_channels].fill(0.0);
+                    for (plane, data) in datas.iter_mut().enumerate() {
+                        let first_channel = if planar { plane } else { 0 };
+                        if first_channel >= n_channels {
+                            break;
+                        }
+                        let offset = data.chunk().offset() as usize;
+                        let size = data.chunk().size() as usize;
+                        let Some(bytes) = data.data() else {
+                            continue;
+                        };
+                        let end = (offset + size).min(bytes.len());
+                        sample_format.decode_plane(
+                            &bytes[offset.min(end)..end],
+                            samples,
+                            n_channels,
+                            first_channel,
+                            plane_channels,
+                            n_frames,
+                        );
+                    }
+                    if let Some(profile) = user_data.analyzer.profile() {
+                        profile.add(Stage::Decode, started.elapsed());
+                    }
+
+                    let rate = state.format.rate();
+                    if state.channel_map_changed {
+                        state.channel_map_changed = false;
+                        let positioned = !state
+                            .format
+                            .flags()
+                            .contains(spa::param::audio::AudioInfoRawFlags::UNPOSITIONED);
+                        let positions = positioned.then(|| state.format.position());
+                        if let Some(events) = &mut user_data.events {
+                            let _ = events.push(Event::ChannelMap {
+                                n_channels,
+                                positions,
+                            });
+                        }
+                    }
+                    if n_frames != state.quantum {
+                        state.quantum = n_frames;
+                        if let Some(events) = &mut user_data.events {
+                            let _ = events.push(Event::Quantum {
+                                frames: n_frames,
+                                rate,
+                            });
+                        }
+                    }
+                    user_data.analyze(n_frames, n_channels);
+                }
+            }
+        })
+        .register()?;
+
+    Ok(Capture {
+        _listener: listener,
+        stream,
+        target_id,
+    })
+}
+
+/// Run the main loop for `delay`. Returns `false` if something else quit
+/// it first, e.g. Ctrl-C.
+fn wait(mainloop: &pw::main_loop::MainLoopRc, delay: Duration) -> Result<bool, pw::Error> {
+    let elapsed = Rc::new(Cell::new(false));
+    let elapsed_timer = elapsed.clone();
+    let mainloop_weak = mainloop.downgrade();
+    let timer = mainloop.loop_().add_timer(move |_| {
+        elapsed_timer.set(true);
+        if let Some(mainloop) = mainloop_weak.upgrade() {
+            mainloop.quit();
+        }
+    });
+    timer.update_timer(Some(delay), None).into_sync_result()?;
+    mainloop.run();
+    Ok(elapsed.get())
+}
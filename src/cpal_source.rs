@@ -0,0 +1,174 @@
+//! Capturing through cpal, for systems without PipeWire.
+
+use crate::source::AudioSource;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, Sample, SizedSample};
+use std::fmt;
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+/// Seconds of audio that may pile up between reads before samples are
+/// dropped.
+const BUFFER_SECONDS: usize = 2;
+
+/// How long the device may go without delivering before reads fail.
+const STALL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Why a [`CpalSource`] couldn't be opened or read.
+#[derive(Debug)]
+pub enum CpalError {
+    /// There is no default input device, or none with the given name.
+    NoDevice(Option<String>),
+    /// The device's native sample format isn't one we decode.
+    UnsupportedFormat(cpal::SampleFormat),
+    /// cpal failed to query or start the device.
+    Backend(String),
+    /// The device stopped delivering samples.
+    Stalled,
+}
+
+impl fmt::Display for CpalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpalError::NoDevice(Some(name)) => write!(f, "no input device named \"{}\"", name),
+            CpalError::NoDevice(None) => write!(f, "no default input device"),
+            CpalError::UnsupportedFormat(format) => {
+                write!(f, "unsupported sample format {}", format)
+            }
+            CpalError::Backend(err) => write!(f, "{}", err),
+            CpalError::Stalled => write!(f, "input device stopped delivering samples"),
+        }
+    }
+}
+
+impl std::error::Error for CpalError {}
+
+/// Interleaved `f32` frames captured from an input device through cpal.
+///
+/// cpal calls back on its own thread, which only copies into a lock-free
+/// ring buffer and wakes the thread that opened the source;
+/// [`read`](AudioSource::read) takes the samples out there, so that is the
+/// thread to read from. Dropping the source stops the stream.
+pub struct CpalSource {
+    n_channels: usize,
+    rate: u32,
+    samples: rtrb::Consumer<f32>,
+    /// When samples last came in, to notice the device stalling.
+    delivered: Instant,
+    _stream: cpal::Stream,
+}
+
+impl CpalSource {
+    /// Start capturing from the input device called `name`, or the default
+    /// one, in its default configuration.
+    pub fn open(name: Option<&str>) -> Result<Self, CpalError> {
+        let host = cpal::default_host();
+        let device = match name {
+            Some(name) => host
+                .input_devices()
+                .map_err(|err| CpalError::Backend(err.to_string()))?
+                .find(|device| device.name().is_ok_and(|n| n == name)),
+            None => host.default_input_device(),
+        }
+        .ok_or_else(|| CpalError::NoDevice(name.map(String::from)))?;
+        let supported = device
+            .default_input_config()
+            .map_err(|err| CpalError::Backend(err.to_string()))?;
+        let config = supported.config();
+        let n_channels = config.channels as usize;
+        let capacity = config.sample_rate.0 as usize * n_channels * BUFFER_SECONDS;
+        let (producer, consumer) = rtrb::RingBuffer::new(capacity.max(1));
+        let reader = thread::current();
+
+        let stream = match supported.sample_format() {
+            cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config, producer, reader),
+            cpal::SampleFormat::I32 => build_stream::<i32>(&device, &config, producer, reader),
+            cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config, producer, reader),
+            cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config, producer, reader),
+            format => return Err(CpalError::UnsupportedFormat(format)),
+        }
+        .map_err(|err| CpalError::Backend(err.to_string()))?;
+        stream
+            .play()
+            .map_err(|err| CpalError::Backend(err.to_string()))?;
+
+        Ok(CpalSource {
+            n_channels,
+            rate: config.sample_rate.0,
+            samples: consumer,
+            delivered: Instant::now(),
+            _stream: stream,
+        })
+    }
+}
+
+impl AudioSource for CpalSource {
+    type Error = CpalError;
+
+    fn n_channels(&self) -> usize {
+        self.n_channels
+    }
+
+    fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Fails with [`CpalError::Stalled`] once the device has delivered
+    /// nothing for a second.
+    fn read(&mut self, samples: &mut [f32], timeout: Duration) -> Result<Option<usize>, CpalError> {
+        let n_channels = self.n_channels.max(1);
+        let len = samples.len() / n_channels * n_channels;
+        let deadline = Instant::now() + timeout;
+        // the callback unparks us after every write; parking may also
+        // return early for no reason, so check again either way
+        while self.samples.slots() < n_channels {
+            let now = Instant::now();
+            if now.duration_since(self.delivered) >= STALL_TIMEOUT {
+                return Err(CpalError::Stalled);
+            }
+            if now >= deadline {
+                return Ok(Some(0));
+            }
+            thread::park_timeout(deadline - now);
+        }
+        self.delivered = Instant::now();
+
+        let n = self.samples.slots().min(len) / n_channels * n_channels;
+        let chunk = self
+            .samples
+            .read_chunk(n)
+            .expect("available slots are always readable");
+        let (first, second) = chunk.as_slices();
+        samples[..first.len()].copy_from_slice(first);
+        samples[first.len()..n].copy_from_slice(second);
+        chunk.commit_all();
+        Ok(Some(n / n_channels))
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut producer: rtrb::Producer<f32>,
+    reader: Thread,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let n_channels = (config.channels as usize).max(1);
+    device.build_input_stream(
+        config,
+        move |data: &[T], _| {
+            // whole frames only, so channels don't shift when the reader
+            // falls behind
+            let n = data.len().min(producer.slots()) / n_channels * n_channels;
+            if let Ok(chunk) = producer.write_chunk_uninit(n) {
+                chunk.fill_from_iter(data.iter().map(|&sample| f32::from_sample(sample)));
+            }
+            reader.unpark();
+        },
+        |err| tracing::error!("cpal stream error: {}", err),
+        None,
+    )
+}
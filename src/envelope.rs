@@ -0,0 +1,148 @@
+//! Smoothed amplitude envelopes.
+
+use std::str::FromStr;
+
+/// Time constant of a VU meter, which reaches 99% of a step in 300 ms on
+/// the way up and down alike.
+const VU_TIME: f32 = 0.3 / 4.6;
+
+/// Time constants of a peak programme meter, which reads about 1 dB under
+/// a 10 ms burst and falls back 24 dB in 2.8 s.
+const PPM_ATTACK: f32 = 0.005;
+const PPM_RELEASE: f32 = 2.8 / (24.0 / 8.686);
+
+/// Which per-channel level an envelope follows, as given by `--envelope`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EnvelopeDetector {
+    /// The absolute sample peak, snappy on transients.
+    Peak,
+    /// The RMS level, closer to how loud the buffer sounds.
+    #[default]
+    Rms,
+}
+
+impl FromStr for EnvelopeDetector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "peak" => Ok(EnvelopeDetector::Peak),
+            "rms" => Ok(EnvelopeDetector::Rms),
+            _ => Err(format!(
+                "unknown envelope detector \"{}\", expected peak or rms",
+                s
+            )),
+        }
+    }
+}
+
+/// How a level meter responds to changes, as given by `--ballistics`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Ballistics {
+    /// The sample peak of each buffer as is.
+    #[default]
+    Digital,
+    /// The RMS level, averaged over about 300 ms.
+    Vu,
+    /// The sample peak, rising fast and falling slowly.
+    Ppm,
+}
+
+impl Ballistics {
+    /// The level the meter follows.
+    pub fn detector(self) -> EnvelopeDetector {
+        match self {
+            Ballistics::Vu => EnvelopeDetector::Rms,
+            Ballistics::Digital | Ballistics::Ppm => EnvelopeDetector::Peak,
+        }
+    }
+
+    /// A follower with the meter's time constants, `None` for digital
+    /// meters which don't smooth.
+    pub fn follower(self) -> Option<EnvelopeFollower> {
+        match self {
+            Ballistics::Digital => None,
+            Ballistics::Vu => Some(EnvelopeFollower::new(VU_TIME, VU_TIME)),
+            Ballistics::Ppm => Some(EnvelopeFollower::new(PPM_ATTACK, PPM_RELEASE)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Ballistics::Digital => "digital",
+            Ballistics::Vu => "vu",
+            Ballistics::Ppm => "ppm",
+        }
+    }
+}
+
+impl FromStr for Ballistics {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "digital" => Ok(Ballistics::Digital),
+            "vu" => Ok(Ballistics::Vu),
+            "ppm" => Ok(Ballistics::Ppm),
+            _ => Err(format!(
+                "unknown ballistics \"{}\", expected vu, ppm or digital",
+                s
+            )),
+        }
+    }
+}
+
+/// One-pole smoothing of a level that is measured once per buffer, with
+/// separate time constants for rising and falling.
+#[derive(Clone, Debug)]
+pub struct EnvelopeFollower {
+    attack: f32,
+    release: f32,
+    /// `None` before the first buffer.
+    value: Option<f32>,
+}
+
+impl EnvelopeFollower {
+    /// Follow rises with time constant `attack_secs` and falls with
+    /// `release_secs`. A time constant of 0 follows immediately.
+    pub fn new(attack_secs: f32, release_secs: f32) -> Self {
+        EnvelopeFollower {
+            attack: attack_secs,
+            release: release_secs,
+            value: None,
+        }
+    }
+
+    /// The smoothed level, `None` before the first buffer.
+    pub fn value(&self) -> Option<f32> {
+        self.value
+    }
+
+    /// Move towards `level`, measured over a buffer of `elapsed` seconds,
+    /// and return the new envelope. The first level is taken as is.
+    pub fn process(&mut self, level: f32, elapsed: f32) -> f32 {
+        let value = match self.value {
+            Some(value) => {
+                let tau = if level > value {
+                    self.attack
+                } else {
+                    self.release
+                };
+                let alpha = if tau > 0.0 {
+                    1.0 - (-elapsed / tau).exp()
+                } else {
+                    1.0
+                };
+                value + alpha * (level - value)
+            }
+            None => level,
+        };
+        self.value = Some(value);
+        value
+    }
+
+    /// Start over as if no buffer had been seen.
+    pub fn reset(&mut self) {
+        self.value = None;
+    }
+}
@@ -0,0 +1,287 @@
+//! Second-order IIR filters.
+
+use crate::processor::ConfigError;
+use std::str::FromStr;
+
+/// Butterworth Q, for the flattest passband from a single section.
+const BUTTERWORTH_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+/// A biquad section in transposed direct form II, with coefficients
+/// normalized so that `a0` is 1.
+///
+/// Coefficients and state are kept in `f64`; the low corner frequencies
+/// some filters use put the poles close enough to the unit circle that
+/// `f32` loses noticeable precision.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    /// A filter from raw coefficients, with `a0` already divided out.
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad {
+            b0: b[0],
+            b1: b[1],
+            b2: b[2],
+            a1: a[0],
+            a2: a[1],
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    /// A second-order Butterworth low-pass at `freq` Hz.
+    pub fn lowpass(freq: f32, rate: u32) -> Self {
+        let (cos, alpha) = Biquad::prototype(freq, rate);
+        let a0 = 1.0 + alpha;
+        Biquad::new(
+            [
+                (1.0 - cos) / 2.0 / a0,
+                (1.0 - cos) / a0,
+                (1.0 - cos) / 2.0 / a0,
+            ],
+            [-2.0 * cos / a0, (1.0 - alpha) / a0],
+        )
+    }
+
+    /// A second-order Butterworth high-pass at `freq` Hz.
+    pub fn highpass(freq: f32, rate: u32) -> Self {
+        let (cos, alpha) = Biquad::prototype(freq, rate);
+        let a0 = 1.0 + alpha;
+        Biquad::new(
+            [
+                (1.0 + cos) / 2.0 / a0,
+                -(1.0 + cos) / a0,
+                (1.0 + cos) / 2.0 / a0,
+            ],
+            [-2.0 * cos / a0, (1.0 - alpha) / a0],
+        )
+    }
+
+    /// `cos(w0)` and `alpha` of the bilinear-transform designs in the
+    /// Audio EQ Cookbook.
+    fn prototype(freq: f32, rate: u32) -> (f64, f64) {
+        let w0 = 2.0 * std::f64::consts::PI * freq as f64 / rate.max(1) as f64;
+        (w0.cos(), w0.sin() / (2.0 * BUTTERWORTH_Q))
+    }
+
+    /// Filter one sample.
+    pub fn process(&mut self, x: f32) -> f32 {
+        let x = x as f64;
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y as f32
+    }
+
+    /// Forget the filter history, as if it had only ever seen silence.
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+}
+
+/// The pre-emphasis filter `y[n] = x[n] - k * x[n - 1]`, run over every
+/// channel, which tilts the spectrum up by about 6 dB per octave. The last
+/// sample of each channel carries over from one buffer to the next.
+#[derive(Clone, Debug, Default)]
+pub struct Preemphasis {
+    coefficient: f32,
+    last: Vec<f32>,
+}
+
+impl Preemphasis {
+    /// A filter with coefficient `k`; 0 leaves the samples alone.
+    pub fn new(k: f32) -> Self {
+        Preemphasis {
+            coefficient: k,
+            last: Vec::new(),
+        }
+    }
+
+    /// Size the history for `n_channels` and clear it. Call this whenever
+    /// the format changes.
+    pub fn configure(&mut self, n_channels: usize) {
+        self.last.clear();
+        self.last.resize(n_channels, 0.0);
+    }
+
+    /// Filter one buffer of interleaved samples in place.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let n_channels = self.last.len();
+        if n_channels == 0 || self.coefficient == 0.0 {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(n_channels) {
+            for (sample, last) in frame.iter_mut().zip(&mut self.last) {
+                let x = *sample;
+                *sample = x - self.coefficient * *last;
+                *last = x;
+            }
+        }
+    }
+}
+
+/// Corner frequency of [`DcBlocker`] in Hz, low enough to leave 20 Hz
+/// within 0.05 dB.
+const DC_BLOCKER_HZ: f64 = 2.0;
+
+/// The one-pole DC-blocking high-pass `y[n] = x[n] - x[n - 1] + r * y[n - 1]`,
+/// run over every channel. The filter state carries over from one buffer to
+/// the next, so unlike subtracting each buffer's mean it leaves anything
+/// slower than the buffer alone, down to the corner at 2 Hz.
+///
+/// Each channel starts out as if its first sample had always been there,
+/// so a constant offset is removed from the first buffer on instead of
+/// decaying away.
+#[derive(Clone, Debug)]
+pub struct DcBlocker {
+    pole: f64,
+    /// Last input and output of each channel, `None` until the first
+    /// sample.
+    state: Vec<Option<(f64, f64)>>,
+}
+
+impl DcBlocker {
+    pub fn new(n_channels: usize, rate: u32) -> Self {
+        DcBlocker {
+            pole: (-2.0 * std::f64::consts::PI * DC_BLOCKER_HZ / rate.max(1) as f64).exp(),
+            state: vec![None; n_channels],
+        }
+    }
+
+    /// Filter the next sample of `channel`.
+    pub fn process(&mut self, channel: usize, x: f32) -> f32 {
+        let x = x as f64;
+        let (last_x, last_y) = self.state[channel].unwrap_or((x, 0.0));
+        let y = x - last_x + self.pole * last_y;
+        self.state[channel] = Some((x, y));
+        y as f32
+    }
+}
+
+/// One `--filter`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterSpec {
+    LowPass(f32),
+    HighPass(f32),
+    /// A high-pass at the lower edge followed by a low-pass at the upper.
+    BandPass(f32, f32),
+}
+
+impl FilterSpec {
+    /// The highest corner frequency, which has to stay below Nyquist.
+    fn max_freq(self) -> f32 {
+        match self {
+            FilterSpec::LowPass(freq) | FilterSpec::HighPass(freq) => freq,
+            FilterSpec::BandPass(_, high) => high,
+        }
+    }
+
+    fn sections(self, rate: u32, sections: &mut Vec<Biquad>) {
+        match self {
+            FilterSpec::LowPass(freq) => sections.push(Biquad::lowpass(freq, rate)),
+            FilterSpec::HighPass(freq) => sections.push(Biquad::highpass(freq, rate)),
+            FilterSpec::BandPass(low, high) => {
+                sections.push(Biquad::highpass(low, rate));
+                sections.push(Biquad::lowpass(high, rate));
+            }
+        }
+    }
+}
+
+/// Parses `"lowpass:200"`, `"highpass:100"` or `"bandpass:300-3000"`.
+impl FromStr for FilterSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, freqs) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <kind>:<frequency>, got \"{}\"", s))?;
+        let freq = |freq: &str| -> Result<f32, String> {
+            match freq.trim().parse::<f32>() {
+                Ok(freq) if freq > 0.0 && freq.is_finite() => Ok(freq),
+                _ => Err(format!("invalid frequency \"{}\"", freq)),
+            }
+        };
+        match kind.trim() {
+            "lowpass" => Ok(FilterSpec::LowPass(freq(freqs)?)),
+            "highpass" => Ok(FilterSpec::HighPass(freq(freqs)?)),
+            "bandpass" => {
+                let (low, high) = freqs
+                    .split_once('-')
+                    .ok_or_else(|| format!("expected <low>-<high>, got \"{}\"", freqs))?;
+                let (low, high) = (freq(low)?, freq(high)?);
+                if low >= high {
+                    return Err(format!("band {}-{} is empty", low, high));
+                }
+                Ok(FilterSpec::BandPass(low, high))
+            }
+            _ => Err(format!(
+                "unknown filter \"{}\", expected lowpass, highpass or bandpass",
+                kind
+            )),
+        }
+    }
+}
+
+/// A chain of `--filter`s run over every channel in order.
+#[derive(Clone, Debug, Default)]
+pub struct FilterChain {
+    specs: Vec<FilterSpec>,
+    /// The sections of every filter, per channel.
+    channels: Vec<Vec<Biquad>>,
+}
+
+impl FilterChain {
+    pub fn new(specs: Vec<FilterSpec>) -> Self {
+        FilterChain {
+            specs,
+            channels: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.specs.is_empty()
+    }
+
+    /// Compute the coefficients for a stream of `n_channels` at `rate` and
+    /// clear the filter history. Call this whenever the format changes.
+    pub fn configure(&mut self, n_channels: usize, rate: u32) -> Result<(), ConfigError> {
+        let nyquist = rate as f32 / 2.0;
+        if let Some(spec) = self.specs.iter().find(|spec| spec.max_freq() >= nyquist) {
+            return Err(ConfigError::FilterAboveNyquist {
+                freq: spec.max_freq(),
+                rate,
+            });
+        }
+        let mut sections = Vec::new();
+        for spec in &self.specs {
+            spec.sections(rate, &mut sections);
+        }
+        self.channels = vec![sections; n_channels];
+        Ok(())
+    }
+
+    /// Filter one buffer of interleaved samples in place.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let n_channels = self.channels.len();
+        if n_channels == 0 || self.specs.is_empty() {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(n_channels) {
+            for (sample, sections) in frame.iter_mut().zip(&mut self.channels) {
+                *sample = sections
+                    .iter_mut()
+                    .fold(*sample, |x, section| section.process(x));
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Test signals, for exercising the analysis without a capture device.
+
+use std::f64::consts::TAU;
+use std::fmt;
+use std::str::FromStr;
+
+/// A `--synthetic` test signal.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Signal {
+    /// A sine wave at a frequency in Hz, as `sine:440` or just `440`.
+    Sine(f64),
+    /// A logarithmic sweep from one frequency to another over `secs`,
+    /// starting over when done, as `sweep:20-20000:10`.
+    Sweep { from: f64, to: f64, secs: f64 },
+    /// Noise with equal power per Hz.
+    White,
+    /// Noise with equal power per octave.
+    Pink,
+    /// Sines at several frequencies at once, as `multitone:440,1000`.
+    Multitone(Vec<f64>),
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Signal::Sine(freq) => write!(f, "{} Hz", freq),
+            Signal::Sweep { from, to, secs } => {
+                write!(f, "sweep {}-{} Hz over {} s", from, to, secs)
+            }
+            Signal::White => write!(f, "white noise"),
+            Signal::Pink => write!(f, "pink noise"),
+            Signal::Multitone(freqs) => {
+                let freqs: Vec<String> = freqs.iter().map(f64::to_string).collect();
+                write!(f, "{} Hz", freqs.join(" + "))
+            }
+        }
+    }
+}
+
+impl FromStr for Signal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, args) = s.split_once(':').unwrap_or((s, ""));
+        match (kind, args) {
+            ("white", "") => Ok(Signal::White),
+            ("pink", "") => Ok(Signal::Pink),
+            ("sine", freq) => Ok(Signal::Sine(parse_positive(freq, "frequency")?)),
+            ("sweep", args) => {
+                let (range, secs) = args
+                    .split_once(':')
+                    .ok_or_else(|| format!("expected sweep:F0-F1:SECONDS, got \"{}\"", s))?;
+                let (from, to) = range
+                    .split_once('-')
+                    .ok_or_else(|| format!("expected sweep:F0-F1:SECONDS, got \"{}\"", s))?;
+                Ok(Signal::Sweep {
+                    from: parse_positive(from, "frequency")?,
+                    to: parse_positive(to, "frequency")?,
+                    secs: parse_positive(secs, "duration")?,
+                })
+            }
+            ("multitone", freqs) => Ok(Signal::Multitone(
+                freqs
+                    .split(',')
+                    .map(|freq| parse_positive(freq, "frequency"))
+                    .collect::<Result<_, _>>()?,
+            )),
+            // a bare frequency, as --synthetic always took
+            (freq, "") if freq.parse::<f64>().is_ok() => {
+                Ok(Signal::Sine(parse_positive(freq, "frequency")?))
+            }
+            _ => Err(format!(
+                "unknown signal \"{}\", expected sine:HZ, sweep:F0-F1:SECONDS, white, pink or multitone:HZ,HZ,...",
+                s
+            )),
+        }
+    }
+}
+
+fn parse_positive(s: &str, what: &str) -> Result<f64, String> {
+    match s.trim().parse::<f64>() {
+        Ok(value) if value > 0.0 && value.is_finite() => Ok(value),
+        _ => Err(format!("expected a positive {}, got \"{}\"", what, s)),
+    }
+}
+
+/// Generates a [`Signal`], written to every channel.
+#[derive(Clone, Debug)]
+pub struct SignalGenerator {
+    signal: Signal,
+    rate: u32,
+    amplitude: f32,
+    /// Phase of the next frame of each sine, in cycles.
+    phases: Vec<f64>,
+    /// Frames into the current sweep.
+    position: u64,
+    /// xorshift state for the noise.
+    seed: u64,
+    /// Filter state turning white noise pink.
+    pink: [f32; 3],
+}
+
+impl SignalGenerator {
+    /// Generate `signal` at `rate` with peaks of about `amplitude`. Tones
+    /// of a multitone share the amplitude so their sum doesn't clip.
+    pub fn new(signal: Signal, rate: u32, amplitude: f32) -> Self {
+        let n_sines = match &signal {
+            Signal::Multitone(freqs) => freqs.len(),
+            _ => 1,
+        };
+        SignalGenerator {
+            signal,
+            rate,
+            amplitude,
+            phases: vec![0.0; n_sines],
+            position: 0,
+            seed: 0x9E37_79B9_7F4A_7C15,
+            pink: [0.0; 3],
+        }
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Fill `samples` with interleaved frames of `n_channels`, carrying the
+    /// phase over so consecutive calls don't click.
+    pub fn fill(&mut self, samples: &mut [f32], n_channels: usize) {
+        let rate = self.rate as f64;
+        for frame in samples.chunks_mut(n_channels.max(1)) {
+            let value = match &self.signal {
+                Signal::Sine(freq) => sine(&mut self.phases[0], freq / rate),
+                Signal::Sweep { from, to, secs } => {
+                    let t = self.position as f64 / rate;
+                    let freq = from * (to / from).powf(t / secs);
+                    self.position += 1;
+                    if self.position as f64 >= secs * rate {
+                        self.position = 0;
+                    }
+                    sine(&mut self.phases[0], freq / rate)
+                }
+                Signal::White => self.white(),
+                Signal::Pink => {
+                    // Paul Kellet's economy filter, within 0.5 dB of -3 dB
+                    // per octave over most of the audio band
+                    let white = self.white();
+                    let b = &mut self.pink;
+                    b[0] = 0.99765 * b[0] + white * 0.0990460;
+                    b[1] = 0.96300 * b[1] + white * 0.2965164;
+                    b[2] = 0.57000 * b[2] + white * 1.0526913;
+                    (b[0] + b[1] + b[2] + white * 0.1848) * 0.15
+                }
+                Signal::Multitone(freqs) => {
+                    let sum: f32 = freqs
+                        .iter()
+                        .zip(&mut self.phases)
+                        .map(|(freq, phase)| sine(phase, freq / rate))
+                        .sum();
+                    sum / freqs.len().max(1) as f32
+                }
+            };
+            frame.fill(self.amplitude * value);
+        }
+    }
+
+    /// Uniform noise in [-1, 1).
+    fn white(&mut self) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        (self.seed >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
+
+/// The sine at `phase`, in cycles, stepping it on by `step`.
+fn sine(phase: &mut f64, step: f64) -> f32 {
+    let value = (*phase * TAU).sin() as f32;
+    *phase = (*phase + step).fract();
+    value
+}
@@ -0,0 +1,122 @@
+//! Narrowband tone detection with the Goertzel algorithm.
+
+use std::f32::consts::TAU;
+
+/// Amplitude of the `freq` component of `samples`, scaled so that a full
+/// sine at exactly `freq` with amplitude `a` reads as `a`.
+///
+/// This is a single DFT bin, so it costs one multiply-add per sample and
+/// is much cheaper than an FFT when only a few frequencies matter.
+pub fn goertzel(samples: impl IntoIterator<Item = f32>, freq: f32, rate: u32) -> f32 {
+    let coeff = 2.0 * (TAU * freq / rate as f32).cos();
+    let (mut s1, mut s2) = (0.0f32, 0.0f32);
+    let mut n = 0;
+    for x in samples {
+        let s = x + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s;
+        n += 1;
+    }
+    if n == 0 {
+        return 0.0;
+    }
+    let power = (s1 * s1 + s2 * s2 - coeff * s1 * s2).max(0.0);
+    2.0 * power.sqrt() / n as f32
+}
+
+/// The DTMF row tones in Hz, low group.
+pub const DTMF_ROWS: [f32; 4] = [697.0, 770.0, 852.0, 941.0];
+/// The DTMF column tones in Hz, high group.
+pub const DTMF_COLUMNS: [f32; 4] = [1209.0, 1336.0, 1477.0, 1633.0];
+
+const DTMF_KEYS: [[char; 4]; 4] = [
+    ['1', '2', '3', 'A'],
+    ['4', '5', '6', 'B'],
+    ['7', '8', '9', 'C'],
+    ['*', '0', '#', 'D'],
+];
+
+/// Weakest tone amplitude accepted as part of a digit.
+const DTMF_MIN_AMPLITUDE: f32 = 0.01;
+
+/// How much the strongest tone of each group has to exceed the runner-up.
+const DTMF_MIN_RATIO: f32 = 2.0;
+
+/// Decode a DTMF digit from tone magnitudes measured at `freqs`, e.g. by
+/// [`goertzel`]. All eight DTMF frequencies have to be among `freqs`.
+/// Returns `None` unless exactly one row and one column tone clearly
+/// dominate their groups.
+pub fn dtmf_digit(freqs: &[f32], magnitudes: &[f32]) -> Option<char> {
+    let magnitude = |tone: f32| -> Option<f32> {
+        let i = freqs.iter().position(|&f| f == tone)?;
+        magnitudes.get(i).copied()
+    };
+    let strongest = |tones: &[f32; 4]| -> Option<usize> {
+        let mut mags = [0.0; 4];
+        for (mag, &tone) in mags.iter_mut().zip(tones) {
+            *mag = magnitude(tone)?;
+        }
+        let (best, &peak) = mags.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1))?;
+        let runner_up = mags
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != best)
+            .map(|(_, &mag)| mag)
+            .fold(0.0, f32::max);
+        (peak >= DTMF_MIN_AMPLITUDE && peak >= runner_up * DTMF_MIN_RATIO).then_some(best)
+    };
+    let row = strongest(&DTMF_ROWS)?;
+    let column = strongest(&DTMF_COLUMNS)?;
+    Some(DTMF_KEYS[row][column])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 8000;
+
+    fn tones(freqs: &[f32], n: usize) -> Vec<f32> {
+        (0..n)
+            .map(|i| {
+                let t = i as f32 / RATE as f32;
+                freqs.iter().map(|f| 0.5 * (TAU * f * t).sin()).sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_the_amplitude_on_its_bin() {
+        // 1000 Hz is exactly 200 cycles in 1600 samples
+        let samples = tones(&[1000.0], 1600);
+        let amplitude = goertzel(samples.iter().copied(), 1000.0, RATE);
+        assert!((amplitude - 0.5).abs() < 0.001, "{}", amplitude);
+    }
+
+    #[test]
+    fn ignores_other_bins() {
+        let samples = tones(&[1000.0], 1600);
+        let amplitude = goertzel(samples.iter().copied(), 1500.0, RATE);
+        assert!(amplitude < 0.001, "{}", amplitude);
+    }
+
+    #[test]
+    fn empty_input_reads_zero() {
+        assert_eq!(goertzel([], 1000.0, RATE), 0.0);
+    }
+
+    #[test]
+    fn decodes_dtmf() {
+        let freqs: Vec<f32> = DTMF_ROWS.iter().chain(&DTMF_COLUMNS).copied().collect();
+        let magnitudes = |samples: &[f32]| -> Vec<f32> {
+            freqs
+                .iter()
+                .map(|&f| goertzel(samples.iter().copied(), f, RATE))
+                .collect()
+        };
+        let five = tones(&[770.0, 1336.0], 800);
+        assert_eq!(dtmf_digit(&freqs, &magnitudes(&five)), Some('5'));
+        let silence = vec![0.0; 800];
+        assert_eq!(dtmf_digit(&freqs, &magnitudes(&silence)), None);
+    }
+}
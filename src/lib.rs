@@ -0,0 +1,43 @@
+//! Audio analysis behind the `rust-audio-monitor` capture binary.
+//!
+//! Everything in here works on plain interleaved `f32` samples, so it can
+//! be driven from the PipeWire `process` callback, a file or a test without
+//! depending on PipeWire itself.
+
+mod agc;
+mod analyzer;
+#[cfg(feature = "cpal")]
+mod cpal_source;
+mod envelope;
+mod filter;
+mod generator;
+mod goertzel;
+mod loudness;
+mod metrics;
+mod pitch;
+mod playback;
+mod processor;
+mod profile;
+mod record;
+mod sample;
+mod source;
+mod true_peak;
+
+pub use agc::AutoGain;
+pub use analyzer::{Analysis, Analyzer, AnalyzerConfig};
+#[cfg(feature = "cpal")]
+pub use cpal_source::{CpalError, CpalSource};
+pub use envelope::{Ballistics, EnvelopeDetector, EnvelopeFollower};
+pub use filter::{Biquad, DcBlocker, FilterChain, FilterSpec, Preemphasis};
+pub use generator::{Signal, SignalGenerator};
+pub use goertzel::{DTMF_COLUMNS, DTMF_ROWS, dtmf_digit, goertzel};
+pub use loudness::{Loudness, LoudnessMeter};
+pub use metrics::{Metrics, serve_metrics};
+pub use pitch::{Note, Pitch, PitchDetector};
+pub use playback::WavSource;
+pub use processor::{AudioProcessor, ChannelGains, ConfigError, Downmix, Level, ProcessorConfig};
+pub use profile::{Profile, Stage};
+pub use record::WavRecorder;
+pub use sample::{SampleFormat, sanitize};
+pub use source::AudioSource;
+pub use true_peak::TruePeakMeter;
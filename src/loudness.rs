@@ -0,0 +1,269 @@
+//! Loudness in LUFS following ITU-R BS.1770.
+
+use crate::filter::Biquad;
+use std::f64::consts::PI;
+
+/// Length of the steps gating blocks advance by. Momentary loudness and
+/// the gating blocks span `MOMENTARY_STEPS` of them, i.e. 400 ms with 75%
+/// overlap, and short-term loudness `SHORT_TERM_STEPS`, i.e. 3 s.
+const STEP_SECONDS: f64 = 0.1;
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+
+/// Blocks quieter than this, in LUFS, never count towards the integrated
+/// loudness.
+const ABSOLUTE_GATE: f64 = -70.0;
+/// Blocks more than this many LU below the loudness of everything above
+/// the absolute gate don't either.
+const RELATIVE_GATE: f64 = -10.0;
+
+/// The gating blocks are kept as a histogram rather than a list, so the
+/// integrated loudness doesn't need memory that grows with the capture.
+/// Bins are 0.1 LU wide from the absolute gate up to +30 LUFS, which costs
+/// at most 0.1 LU at the relative gate.
+const HISTOGRAM_STEP: f64 = 0.1;
+const HISTOGRAM_BINS: usize = 1000;
+
+/// Loudness readings in LUFS. Each is `None` until enough audio has been
+/// measured for it, and the integrated loudness while every block so far
+/// was below the absolute gate.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Loudness {
+    /// Over the last 400 ms.
+    pub momentary: Option<f32>,
+    /// Over the last 3 s.
+    pub short_term: Option<f32>,
+    /// Over everything measured, gated.
+    pub integrated: Option<f32>,
+}
+
+/// Measures momentary, short-term and integrated loudness of a stream.
+///
+/// Every channel is K-weighted and weighted 1.0, as BS.1770 does for left,
+/// right and centre; the 1.41 weight of surround channels isn't applied,
+/// since channel positions aren't known here. Everything is allocated up
+/// front, so [`process`](Self::process) is safe on a real-time thread.
+#[derive(Clone, Debug)]
+pub struct LoudnessMeter {
+    /// The two K-weighting stages for each channel.
+    filters: Vec<[Biquad; 2]>,
+    step_frames: usize,
+    /// Frames and summed squares of the step in progress.
+    frames: usize,
+    energy: f64,
+    /// Mean square, summed over channels, of the most recent steps; `next`
+    /// is the oldest.
+    steps: [f64; SHORT_TERM_STEPS],
+    next: usize,
+    /// Steps completed so far, saturating at `SHORT_TERM_STEPS`.
+    n_steps: usize,
+    /// Gating blocks above the absolute gate, as count and summed mean
+    /// square per 0.1 LU.
+    histogram: Vec<(u64, f64)>,
+}
+
+impl LoudnessMeter {
+    pub fn new(n_channels: usize, rate: u32) -> Self {
+        LoudnessMeter {
+            filters: vec![k_weighting(rate); n_channels],
+            step_frames: ((rate as f64 * STEP_SECONDS).round() as usize).max(1),
+            frames: 0,
+            energy: 0.0,
+            steps: [0.0; SHORT_TERM_STEPS],
+            next: 0,
+            n_steps: 0,
+            histogram: vec![(0, 0.0); HISTOGRAM_BINS],
+        }
+    }
+
+    /// Measure one buffer of interleaved samples.
+    pub fn process(&mut self, samples: &[f32]) {
+        let n_channels = self.filters.len();
+        if n_channels == 0 {
+            return;
+        }
+        for frame in samples.chunks_exact(n_channels) {
+            for (filters, &sample) in self.filters.iter_mut().zip(frame) {
+                let [shelf, high_pass] = filters;
+                let y = high_pass.process(shelf.process(sample)) as f64;
+                self.energy += y * y;
+            }
+            self.frames += 1;
+            if self.frames == self.step_frames {
+                self.finish_step();
+            }
+        }
+    }
+
+    fn finish_step(&mut self) {
+        self.steps[self.next] = self.energy / self.frames as f64;
+        self.next = (self.next + 1) % SHORT_TERM_STEPS;
+        self.n_steps = (self.n_steps + 1).min(SHORT_TERM_STEPS);
+        self.frames = 0;
+        self.energy = 0.0;
+
+        let Some(block) = self.mean_square(MOMENTARY_STEPS) else {
+            return;
+        };
+        let loudness = lufs(block);
+        if loudness > ABSOLUTE_GATE {
+            let bin =
+                (((loudness - ABSOLUTE_GATE) / HISTOGRAM_STEP) as usize).min(HISTOGRAM_BINS - 1);
+            self.histogram[bin].0 += 1;
+            self.histogram[bin].1 += block;
+        }
+    }
+
+    /// Mean of the last `n` steps, if that many have completed.
+    fn mean_square(&self, n: usize) -> Option<f64> {
+        if self.n_steps < n {
+            return None;
+        }
+        let sum: f64 = (1..=n)
+            .map(|age| self.steps[(self.next + SHORT_TERM_STEPS - age) % SHORT_TERM_STEPS])
+            .sum();
+        Some(sum / n as f64)
+    }
+
+    /// Gated loudness of every block measured so far.
+    fn integrated(&self) -> Option<f64> {
+        let mean = |bins: &[(u64, f64)]| -> Option<f64> {
+            let (count, energy) = bins
+                .iter()
+                .fold((0, 0.0), |(count, energy), &(n, e)| (count + n, energy + e));
+            (count > 0).then(|| energy / count as f64)
+        };
+        let gate = lufs(mean(&self.histogram)?) + RELATIVE_GATE;
+        let first =
+            (((gate - ABSOLUTE_GATE) / HISTOGRAM_STEP).max(0.0) as usize).min(HISTOGRAM_BINS - 1);
+        mean(&self.histogram[first..]).map(lufs)
+    }
+
+    pub fn loudness(&self) -> Loudness {
+        Loudness {
+            momentary: self.mean_square(MOMENTARY_STEPS).map(|z| lufs(z) as f32),
+            short_term: self.mean_square(SHORT_TERM_STEPS).map(|z| lufs(z) as f32),
+            integrated: self.integrated().map(|l| l as f32),
+        }
+    }
+}
+
+/// Loudness of a K-weighted mean square summed over channels.
+fn lufs(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+/// The BS.1770 K-weighting filter for `rate`: a high shelf modelling the
+/// head, followed by the RLB high-pass. The standard only gives
+/// coefficients for 48 kHz; these are derived from its analog prototypes,
+/// so they match it there and carry over to other rates.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = rate.max(1) as f64;
+
+    let (gain_db, freq, q) = (3.999843853973347, 1681.974450955533, 0.7071752369554196);
+    let k = (PI * freq / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let (freq, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * freq / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::TAU;
+
+    /// `seconds` of a mono sine at `amplitude`.
+    fn sine(freq: f64, amplitude: f64, rate: u32, seconds: f64) -> Vec<f32> {
+        let n_frames = (rate as f64 * seconds).round() as usize;
+        (0..n_frames)
+            .map(|i| (amplitude * (TAU * freq * i as f64 / rate as f64).sin()) as f32)
+            .collect()
+    }
+
+    fn assert_close(got: Option<f32>, want: f32, tolerance: f32) {
+        let got = got.expect("no reading");
+        assert!(
+            (got - want).abs() <= tolerance,
+            "{} is not within {} of {}",
+            got,
+            tolerance,
+            want
+        );
+    }
+
+    #[test]
+    fn full_scale_sine_reads_minus_3_lufs() {
+        // the BS.1770 reference: a 0 dBFS 997 Hz sine on one channel
+        for rate in [48000, 44100] {
+            let mut meter = LoudnessMeter::new(1, rate);
+            meter.process(&sine(997.0, 1.0, rate, 5.0));
+            let loudness = meter.loudness();
+            assert_close(loudness.momentary, -3.01, 0.05);
+            assert_close(loudness.short_term, -3.01, 0.05);
+            assert_close(loudness.integrated, -3.01, 0.05);
+        }
+    }
+
+    #[test]
+    fn readings_wait_for_enough_audio() {
+        let mut meter = LoudnessMeter::new(1, 48000);
+        meter.process(&sine(997.0, 0.5, 48000, 0.3));
+        assert_eq!(meter.loudness(), Loudness::default());
+
+        // the first gating block is complete at 400 ms
+        meter.process(&sine(997.0, 0.5, 48000, 0.1));
+        let loudness = meter.loudness();
+        assert!(loudness.momentary.is_some());
+        assert!(loudness.integrated.is_some());
+        assert_eq!(loudness.short_term, None);
+
+        meter.process(&sine(997.0, 0.5, 48000, 2.5));
+        assert_eq!(meter.loudness().short_term, None);
+        meter.process(&sine(997.0, 0.5, 48000, 0.1));
+        assert!(meter.loudness().short_term.is_some());
+    }
+
+    #[test]
+    fn silence_has_no_integrated_loudness() {
+        let mut meter = LoudnessMeter::new(2, 48000);
+        meter.process(&vec![0.0; 2 * 48000]);
+        assert!(meter.loudness().momentary.is_some());
+        assert_eq!(meter.loudness().integrated, None);
+    }
+
+    #[test]
+    fn gates_leave_out_quiet_blocks() {
+        let mut meter = LoudnessMeter::new(1, 48000);
+        meter.process(&sine(997.0, 1.0, 48000, 10.0));
+        let loud = meter.loudness().integrated;
+        assert_close(loud, -3.01, 0.05);
+
+        // near-silence is below the absolute gate
+        meter.process(&sine(997.0, 1e-4, 48000, 10.0));
+        assert_close(meter.loudness().integrated, loud.unwrap(), 0.1);
+
+        // -20 dB is above the absolute gate, but more than 10 LU below the
+        // rest, so the relative gate leaves it out too
+        meter.process(&sine(997.0, 0.1, 48000, 10.0));
+        assert_close(meter.loudness().integrated, loud.unwrap(), 0.1);
+        assert_close(meter.loudness().momentary, -23.01, 0.05);
+    }
+}
@@ -5,173 +5,2268 @@
 //!
 //! example: https://docs.pipewire.org/audio-capture_8c-example.html
 
-use clap::Parser;
-use pipewire as pw;
-use pw::{properties::properties, spa};
-use spa::param::format::{MediaSubtype, MediaType};
-use spa::param::format_utils;
-use spa::pod::Pod;
-use std::convert::TryInto;
-use std::mem;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+#[cfg(feature = "cpal")]
+use rust_audio_monitor::CpalSource;
+use rust_audio_monitor::{
+    Analysis, Analyzer, AnalyzerConfig, AudioProcessor, AudioSource, AutoGain, Ballistics,
+    ChannelGains, ConfigError, DTMF_COLUMNS, DTMF_ROWS, Downmix, EnvelopeDetector, FilterChain,
+    FilterSpec, Level, Loudness, Metrics, Note, Pitch, ProcessorConfig, Profile, SampleFormat,
+    Signal, SignalGenerator, Stage, WavRecorder, WavSource, dtmf_digit, serve_metrics,
+};
+use std::cell::{Cell, RefCell};
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
 
+#[cfg(feature = "pipewire")]
+mod capture;
+
+/// What one stream is analyzed with, and where it reports to.
 struct UserData {
-    format: spa::param::audio::AudioInfoRaw,
-    cursor_move: bool,
+    analyzer: Analyzer,
+    /// Interleaved samples of the current buffer, decoded for `analyzer`.
+    /// Sized for the largest buffer when the format is configured and
+    /// never shrunk, so `process` only writes a prefix and doesn't allocate.
+    samples: Vec<f32>,
+    /// Where `process` reports to the main loop; `None` with `--quiet`.
+    events: Option<rtrb::Producer<Event>>,
+    /// Sequence number of the next reported buffer.
+    seq: u64,
+    /// The `--record` file, opened once the format is known and handed to
+    /// `analyzer`.
+    record_path: Option<PathBuf>,
+    /// Set once `--max-frames` have been analyzed, for the main loop to
+    /// quit.
+    finished: Arc<AtomicBool>,
 }
 
-#[derive(Parser)]
-#[clap(name = "audio-capture", about = "Audio stream capture example")]
-struct Opt {
-    #[clap(short, long, help = "The target object id to connect to")]
-    target: Option<String>,
+impl UserData {
+    /// Run the first `n_frames` of the decoded `samples` through the
+    /// analyzer, which records them, and report the results to the main
+    /// loop.
+    fn analyze(&mut self, n_frames: usize, n_channels: usize) {
+        let UserData {
+            analyzer,
+            samples,
+            events,
+            seq,
+            ..
+        } = self;
+        analyzer.push(&mut samples[..n_frames * n_channels], |analysis| {
+            if analysis.warmup {
+                return;
+            }
+            let stats = Stats::new(*seq, &analysis);
+            *seq += 1;
+            if let Some(events) = events {
+                // a full queue means the main loop is behind; drop the frame
+                let _ = events.push(Event::Levels(stats));
+            }
+        });
+
+        if let Some(profile) = self.analyzer.profile()
+            && profile.since.elapsed() >= PROFILE_INTERVAL
+        {
+            if let Some(events) = &mut self.events {
+                let _ = events.push(Event::Profile(*profile));
+            }
+            *profile = Profile::new();
+        }
+
+        // after the push, so the main loop gets to print the last levels
+        if self.analyzer.is_finished() {
+            self.finished.store(true, Ordering::Release);
+        }
+    }
+
+    /// Get the analysis ready for a newly negotiated format, in buffers of
+    /// up to `max_frames`.
+    fn configure(
+        &mut self,
+        n_channels: usize,
+        rate: u32,
+        max_frames: usize,
+    ) -> Result<(), ConfigError> {
+        self.analyzer.configure(n_channels, rate, max_frames)?;
+        let len = max_frames * n_channels;
+        if self.samples.len() < len {
+            self.samples.resize(len, 0.0);
+        }
+        Ok(())
+    }
+
+    /// Start writing the `--record` file for the negotiated format. A WAV
+    /// file can't change format halfway, so if the stream is renegotiated
+    /// to something else the recording is finished instead.
+    fn start_recording(&mut self, n_channels: usize, rate: u32) -> hound::Result<()> {
+        let Some(path) = &self.record_path else {
+            return Ok(());
+        };
+        match self.analyzer.recorder() {
+            Some(recorder)
+                if recorder.n_channels() as usize == n_channels && recorder.rate() == rate => {}
+            Some(_) => {
+                warn!("format changed, stopped recording to {}", path.display());
+                self.analyzer.set_recorder(None);
+                self.record_path = None;
+            }
+            None => {
+                let recorder = WavRecorder::create(path, n_channels as u16, rate)?;
+                self.analyzer.set_recorder(Some(recorder));
+                info!("recording to {}", path.display());
+            }
+        }
+        Ok(())
+    }
 }
 
-pub fn main() -> Result<(), pw::Error> {
-    pw::init();
+/// Most channels a stream is analyzed in, as many as SPA has positions
+/// for.
+const MAX_CHANNELS: usize = 64;
 
-    let mainloop = pw::main_loop::MainLoopRc::new(None)?;
-    let context = pw::context::ContextRc::new(&mainloop, None)?;
-    let core = context.connect_rc(None)?;
+/// Most `--goertzel` frequencies that can be measured at once.
+const MAX_TONES: usize = 16;
 
-    let data = UserData {
-        format: Default::default(),
-        cursor_move: false,
-    };
+/// How many events the data thread can queue before the main loop drains them.
+const EVENT_QUEUE_SIZE: usize = 64;
 
-    /* Create a simple stream, the simple stream manages the core and remote
-     * objects for you if you don't need to deal with them.
-     *
-     * If you plan to autoconnect your stream, you need to provide at least
-     * media, category and role properties.
-     *
-     * Pass your events and a user_data pointer as the last arguments. This
-     * will inform you about the stream state. The most important event
-     * you need to listen to is the process event where you need to produce
-     * the data.
-     */
-    let props = properties! {
-        *pw::keys::MEDIA_TYPE => "Audio",
-        *pw::keys::MEDIA_CATEGORY => "Capture",
-        *pw::keys::MEDIA_ROLE => "Music",
-    };
+/// How often `--profile` reports the stage timings.
+const PROFILE_INTERVAL: Duration = Duration::from_secs(1);
 
-    // uncomment if you want to capture from the sink monitor ports
-    // props.insert(*pw::keys::STREAM_CAPTURE_SINK, "true");
+/// How often the main loop drains and prints data thread events.
+const PRINT_INTERVAL: Duration = Duration::from_millis(33);
 
-    let stream = pw::stream::StreamBox::new(&core, "audio-capture", props)?;
+/// Format of the `--synthetic` signal, standing in for what a capture
+/// stream would negotiate.
+const SYNTHETIC_RATE: u32 = 48000;
+const SYNTHETIC_CHANNELS: usize = 2;
 
-    let _listener = stream
-        .add_local_listener_with_user_data(data)
-        .param_changed(|_, user_data, id, param| {
-            // NULL means to clear the format
-            let Some(param) = param else {
-                return;
-            };
-            if id != pw::spa::param::ParamType::Format.as_raw() {
-                return;
+/// Frames read from an `AudioSource` at a time, roughly one PipeWire
+/// quantum.
+const PLAY_FRAMES: usize = 1024;
+
+/// Messages from the `process` callback to the main loop.
+///
+/// The callback runs on the real-time data thread, so it never formats
+/// or prints anything itself; it pushes one of these into a lock-free
+/// queue and the main loop does the printing.
+// Levels is large, but boxing it would allocate on the data thread.
+#[allow(clippy::large_enum_variant)]
+// only PipeWire streams have a data thread and a quantum to report
+#[cfg_attr(not(feature = "pipewire"), allow(dead_code))]
+enum Event {
+    Scheduling(Scheduling),
+    OutOfBuffers,
+    /// Buffers now carry this many frames.
+    Quantum {
+        frames: usize,
+        rate: u32,
+    },
+    Levels(Stats),
+    /// The negotiated channel positions, `None` for unpositioned streams.
+    ChannelMap {
+        n_channels: usize,
+        positions: Option<[u32; MAX_CHANNELS]>,
+    },
+    /// Stage timings, every `PROFILE_INTERVAL` with `--profile`.
+    Profile(Profile),
+}
+
+/// How `Printer` shows levels, for `--format`.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    /// An in-place meter for the terminal.
+    Text,
+    /// One JSON object per analyzed buffer, one per line.
+    Json,
+}
+
+/// Where to capture from, for `--backend`.
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    /// A PipeWire node, with the full node selection and reconnecting.
+    /// Needs the `pipewire` feature, which is on by default.
+    Pipewire,
+    /// An input device through cpal, for systems without a PipeWire
+    /// daemon. Needs the `cpal` feature.
+    Cpal,
+}
+
+/// Summary of one processed buffer.
+#[derive(Clone, Copy)]
+struct Stats {
+    /// Counts up by one per analyzed buffer, so gaps show dropped events.
+    seq: u64,
+    /// When the buffer was analyzed on the data thread.
+    captured: Instant,
+    rate: u32,
+    n_frames: usize,
+    n_channels: usize,
+    levels: [Level; MAX_CHANNELS],
+    pitch: Option<Pitch>,
+    /// `None` without `--lufs`.
+    loudness: Option<Loudness>,
+    /// Linear `--agc` gain applied to the buffer before analysis.
+    agc_gain: Option<f32>,
+    /// Linear `--envelope` level.
+    envelope: Option<f32>,
+    /// Channel 0/1 correlation, `None` for mono.
+    correlation: Option<f32>,
+    /// Frames channel 1 lags channel 0, with `--max-delay-us`.
+    delay: Option<f32>,
+    /// Whether the input is past `--silence-threshold`.
+    silent: bool,
+    /// Whether a channel's crest factor is past `--crest-threshold`.
+    transient: bool,
+    /// NaN or infinite samples replaced with silence in the buffer.
+    non_finite: usize,
+    n_tones: usize,
+    tones: [f32; MAX_TONES],
+}
+
+impl Stats {
+    /// The results of `analysis`, numbered `seq`.
+    fn new(seq: u64, analysis: &Analysis) -> Self {
+        let processor = analysis.processor;
+        let mut stats = Stats {
+            seq,
+            captured: Instant::now(),
+            rate: analysis.rate,
+            n_frames: analysis.samples.len() / analysis.n_channels.max(1),
+            n_channels: analysis.levels.len(),
+            levels: [Level::default(); MAX_CHANNELS],
+            pitch: processor.pitch(),
+            loudness: processor.loudness(),
+            agc_gain: analysis.agc_gain,
+            envelope: processor.envelope(),
+            correlation: processor.correlation(),
+            delay: processor.delay(),
+            silent: processor.is_silent(),
+            transient: processor.is_transient(),
+            non_finite: analysis.non_finite,
+            n_tones: 0,
+            tones: [0.0; MAX_TONES],
+        };
+        stats.levels[..stats.n_channels].copy_from_slice(analysis.levels);
+        let tones = processor.tone_magnitudes();
+        stats.n_tones = tones.len().min(MAX_TONES);
+        stats.tones[..stats.n_tones].copy_from_slice(&tones[..stats.n_tones]);
+        stats
+    }
+
+    fn levels(&self) -> &[Level] {
+        &self.levels[..self.n_channels]
+    }
+
+    fn tones(&self) -> &[f32] {
+        &self.tones[..self.n_tones]
+    }
+}
+
+/// Prints data thread events on the main loop, and keeps the
+/// `--metrics-port` metrics up to date from them.
+struct Printer {
+    /// The `--target` this printer reports on when there are several,
+    /// shown with its levels and status messages.
+    label: Option<String>,
+    events: rtrb::Consumer<Event>,
+    /// Only update the metrics, for `--quiet`.
+    quiet: bool,
+    metrics: Option<Arc<Mutex<Metrics>>>,
+    format: OutputFormat,
+    /// Lines drawn by the last meter update, to move the cursor back over.
+    lines: usize,
+    /// The newest levels for the meter, and whether they still have to be
+    /// drawn.
+    latest: Option<Stats>,
+    redraw: bool,
+    /// Whether to draw a pitch line under the channels, for `--pitch`.
+    show_pitch: bool,
+    /// How the channel bars move, for `--ballistics`.
+    ballistics: Ballistics,
+    /// Whether to report crest factors and transients, for
+    /// `--crest-threshold`.
+    show_transients: bool,
+    /// Whether to draw a delay line for stereo, for `--max-delay-us`.
+    show_delay: bool,
+    /// Tuning of A4 in Hz, for naming the pitch.
+    a4: f32,
+    /// The `--channel` the tones and pitch are measured on.
+    channel: Option<usize>,
+    /// Frequencies of the `--goertzel` tones, matching `Stats::tones`.
+    tones: Vec<f32>,
+    /// Decode DTMF digits from the tones, for `--dtmf`.
+    dtmf: bool,
+    /// Channel positions of the stream, `None` until reported and for
+    /// unpositioned streams.
+    positions: Option<[u32; MAX_CHANNELS]>,
+    /// Sequence number of the last levels event received.
+    last_seq: Option<u64>,
+    /// Levels events lost to a full queue so far.
+    dropped: u64,
+    /// Times `process` found no buffer to dequeue so far, i.e. the graph
+    /// ran without handing us data.
+    xruns: u64,
+    /// NaN or infinite input samples so far.
+    non_finite: u64,
+    /// Whether the last levels were silent; silent levels are only shown
+    /// once, when the input goes quiet.
+    silent: bool,
+    /// `Some` with `--dump-once`, set once the levels have been printed.
+    dumped: Option<Rc<Cell<bool>>>,
+    /// The `--max-fps` limit, to report the analysis rate it works out to.
+    max_fps: Option<f64>,
+}
+
+impl Printer {
+    /// Name of the position of channel `c`, "unknown" if the stream didn't
+    /// report one.
+    fn position(&self, c: usize) -> String {
+        match self.positions {
+            Some(positions) => channel_name(positions.get(c).copied().unwrap_or(0)),
+            None => String::from("unknown"),
+        }
+    }
+
+    /// Prefix for status messages, naming the target when there are several.
+    fn tag(&self) -> String {
+        match &self.label {
+            Some(label) => format!("{}: ", label),
+            None => String::new(),
+        }
+    }
+
+    /// Handle the queued events. JSON lines are printed right away; the
+    /// meter is left for [`draw`] to redraw with the newest levels.
+    fn drain(&mut self) {
+        while let Ok(event) = self.events.pop() {
+            match event {
+                Event::Scheduling(scheduling) => {
+                    info!("{}data thread scheduling: {}", self.tag(), scheduling)
+                }
+                Event::OutOfBuffers => self.xruns += 1,
+                Event::Quantum { frames, rate } => {
+                    info!(
+                        "{}quantum: {} frames ({:.1} ms)",
+                        self.tag(),
+                        frames,
+                        frames as f64 * 1000.0 / rate.max(1) as f64
+                    );
+                    if let Some(fps) = self.max_fps {
+                        // whole buffers are gathered until a period is full
+                        let period = (rate as f64 / fps).ceil() as usize;
+                        let frames = period.div_ceil(frames.max(1)) * frames;
+                        info!(
+                            "{}analyzing every {} frames, {:.1} times a second",
+                            self.tag(),
+                            frames,
+                            rate as f64 / frames.max(1) as f64
+                        );
+                    }
+                }
+                Event::Profile(profile) => {
+                    let stages: Vec<_> = Stage::ALL
+                        .iter()
+                        .map(|&stage| {
+                            format!(
+                                "{} avg:{:.1}us max:{:.1}us",
+                                stage.name(),
+                                profile.average(stage).as_secs_f64() * 1e6,
+                                profile.max[stage as usize].as_secs_f64() * 1e6
+                            )
+                        })
+                        .collect();
+                    info!(
+                        "{}profile over {} buffers: {}",
+                        self.tag(),
+                        profile.buffers,
+                        stages.join(", ")
+                    );
+                }
+                Event::ChannelMap {
+                    n_channels,
+                    positions,
+                } => {
+                    self.positions = positions;
+                    match positions {
+                        Some(positions) => {
+                            let names: Vec<String> = positions[..n_channels]
+                                .iter()
+                                .map(|&position| channel_name(position))
+                                .collect();
+                            info!("{}channel map: {}", self.tag(), names.join(" "));
+                        }
+                        None => info!("{}channel map: unknown", self.tag()),
+                    }
+                }
+                Event::Levels(stats) => {
+                    if let Some(last_seq) = self.last_seq {
+                        self.dropped += stats.seq.saturating_sub(last_seq + 1);
+                    }
+                    self.last_seq = Some(stats.seq);
+                    self.non_finite += stats.non_finite as u64;
+                    if let Some(metrics) = &self.metrics
+                        && let Ok(mut metrics) = metrics.lock()
+                    {
+                        metrics.record(
+                            stats.n_frames,
+                            stats.levels(),
+                            stats.pitch,
+                            stats.correlation,
+                        );
+                    }
+                    let went_silent = stats.silent && !self.silent;
+                    self.silent = stats.silent;
+                    if self.quiet || (stats.silent && !went_silent) {
+                        continue;
+                    }
+                    if let Some(dumped) = self.dumped.clone() {
+                        if !dumped.get() {
+                            match self.format {
+                                OutputFormat::Text => self.print_levels(&stats),
+                                OutputFormat::Json => self.print_json(&stats),
+                            }
+                            dumped.set(true);
+                        }
+                        continue;
+                    }
+                    match self.format {
+                        // only the most recent levels are worth drawing
+                        OutputFormat::Text => {
+                            self.latest = Some(stats);
+                            self.redraw = true;
+                        }
+                        OutputFormat::Json => self.print_json(&stats),
+                    }
+                }
             }
+        }
+        if let Some(metrics) = &self.metrics
+            && let Ok(mut metrics) = metrics.lock()
+        {
+            metrics.dropped = self.dropped;
+            metrics.xruns = self.xruns;
+            metrics.non_finite = self.non_finite;
+        }
+    }
 
-            let (media_type, media_subtype) = match format_utils::parse_format(param) {
-                Ok(v) => v,
-                Err(_) => return,
+    fn print_json(&self, stats: &Stats) {
+        let channels: Vec<_> = stats
+            .levels()
+            .iter()
+            .enumerate()
+            .map(|(c, level)| {
+                let mut channel = serde_json::json!({ "position": self.position(c), "peak": level.peak, "rms": level.rms, "clipped": level.clipped });
+                if let Some(peak_hold) = level.peak_hold {
+                    channel["peak_hold"] = peak_hold.into();
+                }
+                if let Some(meter) = level.meter {
+                    channel[self.ballistics.name()] = meter.into();
+                }
+                if let Some(true_peak) = level.true_peak {
+                    channel["true_peak"] = true_peak.into();
+                    channel["true_peak_over"] = (true_peak > 1.0).into();
+                }
+                if self.show_transients {
+                    channel["crest_factor_db"] =
+                        level.crest_factor().map(|crest| 20.0 * crest.log10()).into();
+                }
+                channel
+            })
+            .collect();
+        let mut line = serde_json::json!({
+            "seq": stats.seq,
+            "sample_rate": stats.rate,
+            "n_frames": stats.n_frames,
+            "dropped": self.dropped,
+            "xruns": self.xruns,
+            "non_finite": self.non_finite,
+            "latency_ms": stats.captured.elapsed().as_secs_f64() * 1000.0,
+            "channels": channels,
+            "correlation": stats.correlation,
+            "silence": stats.silent,
+        });
+        if let Some(label) = &self.label {
+            line["stream"] = label.as_str().into();
+        }
+        if self.show_transients {
+            line["transient"] = stats.transient.into();
+        }
+        if self.show_delay && stats.n_channels >= 2 {
+            line["delay_samples"] = stats.delay.into();
+            line["delay_us"] = stats
+                .delay
+                .map(|delay| delay as f64 * 1e6 / stats.rate as f64)
+                .into();
+        }
+        if let Some(channel) = self.channel {
+            line["analysis_channel"] = channel.into();
+        }
+        if let Some(gain) = stats.agc_gain {
+            line["agc_gain_db"] = (20.0 * gain.log10()).into();
+        }
+        if !self.tones.is_empty() {
+            line["tones"] = self
+                .tones
+                .iter()
+                .zip(stats.tones())
+                .map(
+                    |(freq, magnitude)| serde_json::json!({ "freq": freq, "magnitude": magnitude }),
+                )
+                .collect();
+        }
+        if self.dtmf {
+            line["dtmf"] = dtmf_digit(&self.tones, stats.tones())
+                .map(String::from)
+                .into();
+        }
+        if let Some(envelope) = stats.envelope {
+            line["envelope"] = envelope.into();
+        }
+        if let Some(loudness) = stats.loudness {
+            line["loudness"] = serde_json::json!({
+                "momentary": loudness.momentary,
+                "short_term": loudness.short_term,
+                "integrated": loudness.integrated,
+            });
+        }
+        if self.show_pitch {
+            line["pitch"] = match stats.pitch {
+                Some(pitch) => {
+                    let mut value =
+                        serde_json::json!({ "freq": pitch.freq, "confidence": pitch.confidence });
+                    if let Some(note) = Note::nearest(pitch.freq, self.a4) {
+                        value["note"] = format!("{}{}", note.name, note.octave).into();
+                        value["cents"] = note.cents.into();
+                    }
+                    value
+                }
+                None => serde_json::Value::Null,
             };
+        }
+        println!("{}", line);
+    }
 
-            // only accept raw audio
-            if media_type != MediaType::Audio || media_subtype != MediaSubtype::Raw {
-                return;
+    /// Print the meter for `stats` from the cursor down.
+    fn print_levels(&mut self, stats: &Stats) {
+        self.redraw = false;
+        self.lines = stats.n_channels + 1;
+        let agc = match stats.agc_gain {
+            Some(gain) => format!(" agc:{:+.1}dB", 20.0 * gain.log10()),
+            None => String::new(),
+        };
+        let analysis_channel = match self.channel {
+            Some(channel) => format!(" analyzing:ch{}", channel),
+            None => String::new(),
+        };
+        // only worth a mention once the source has misbehaved
+        let non_finite = match self.non_finite {
+            0 => String::new(),
+            n => format!(" nan:{}", n),
+        };
+        println!(
+            "{}captured {} samples seq:{} dropped:{} xruns:{}{} latency:{:.1}ms{}{} {}",
+            self.tag(),
+            stats.n_frames,
+            stats.seq,
+            self.dropped,
+            self.xruns,
+            non_finite,
+            stats.captured.elapsed().as_secs_f64() * 1000.0,
+            agc,
+            analysis_channel,
+            if stats.silent {
+                "(silence)"
+            } else if stats.transient {
+                "(transient)"
+            } else {
+                "           "
             }
+        );
+        for (c, level) in stats.levels().iter().enumerate() {
+            // the bar follows the meter, the numbers stay per buffer
+            let bar = level.meter.unwrap_or(level.peak);
+            let peak = ((bar * 30.0) as usize).clamp(0, 39);
 
-            // call a helper function to parse the format for us.
-            user_data
-                .format
-                .parse(param)
-                .expect("Failed to parse param changed to AudioInfoRaw");
-
+            let hold = match level.peak_hold {
+                Some(peak_hold) => format!(" hold:{}", peak_hold),
+                None => String::new(),
+            };
+            let meter = match level.meter {
+                Some(meter) => format!(" {}:{}", self.ballistics.name(), meter),
+                None => String::new(),
+            };
+            // OVER once the true peak passes 0 dBTP
+            let true_peak = match level.true_peak {
+                Some(true_peak) => format!(
+                    " tp:{:+.1}dBTP {}",
+                    20.0 * true_peak.log10(),
+                    if true_peak > 1.0 { "OVER" } else { "    " }
+                ),
+                None => String::new(),
+            };
+            let position = match self.positions {
+                Some(_) => format!(" {}", self.position(c)),
+                None => String::new(),
+            };
             println!(
-                "capturing rate:{} channels:{}",
-                user_data.format.rate(),
-                user_data.format.channels()
+                "channel {}{}: |{:>w1$}{:w2$}| peak:{}{}{} rms:{}{} {}",
+                c,
+                position,
+                "*",
+                "",
+                level.peak,
+                hold,
+                true_peak,
+                level.rms,
+                meter,
+                if level.clipped > 0 { "CLIP" } else { "    " },
+                w1 = peak + 1,
+                w2 = 40 - peak
             );
-        })
-        .process(|stream, user_data| match stream.dequeue_buffer() {
-            None => println!("out of buffers"),
-            Some(mut buffer) => {
-                let datas = buffer.datas_mut();
-                if datas.is_empty() {
-                    return;
+        }
+        if stats.n_channels >= 2 {
+            match stats.correlation {
+                Some(correlation) => println!("correlation: {:+.2}", correlation),
+                None => println!("correlation: -    "),
+            }
+            self.lines += 1;
+            if self.show_delay {
+                // pad to overwrite a longer previous line
+                match stats.delay {
+                    Some(delay) => println!(
+                        "delay: {:+.1} samples ({:+.0} us){:10}",
+                        delay,
+                        delay as f64 * 1e6 / stats.rate as f64,
+                        ""
+                    ),
+                    None => println!("delay: -{:30}", ""),
+                }
+                self.lines += 1;
+            }
+        }
+        if self.show_pitch {
+            // pad to overwrite a longer previous line
+            match stats.pitch {
+                Some(pitch) => {
+                    let note = match Note::nearest(pitch.freq, self.a4) {
+                        Some(note) => format!("{} ", note),
+                        None => String::new(),
+                    };
+                    println!(
+                        "pitch: {}({:.1} Hz) confidence:{:.2}{:10}",
+                        note, pitch.freq, pitch.confidence, ""
+                    )
                 }
+                None => println!("pitch: -{:40}", ""),
+            }
+            self.lines += 1;
+        }
+        if let Some(envelope) = stats.envelope {
+            let width = ((envelope * 40.0) as usize).min(40);
+            println!(
+                "envelope: |{:*<w1$}{:w2$}| {:.3}",
+                "",
+                "",
+                envelope,
+                w1 = width,
+                w2 = 40 - width
+            );
+            self.lines += 1;
+        }
+        if let Some(loudness) = stats.loudness {
+            let lufs = |value: Option<f32>| match value {
+                Some(value) => format!("{:.1}", value),
+                None => String::from("-"),
+            };
+            // pad to overwrite a longer previous line
+            println!(
+                "loudness: M:{} S:{} I:{} LUFS{:10}",
+                lufs(loudness.momentary),
+                lufs(loudness.short_term),
+                lufs(loudness.integrated),
+                ""
+            );
+            self.lines += 1;
+        }
+        if !self.tones.is_empty() {
+            let tones: Vec<_> = self
+                .tones
+                .iter()
+                .zip(stats.tones())
+                .map(|(freq, magnitude)| format!("{}Hz:{:.3}", freq, magnitude))
+                .collect();
+            println!("tones: {}", tones.join(" "));
+            self.lines += 1;
+        }
+        if self.dtmf {
+            match dtmf_digit(&self.tones, stats.tones()) {
+                Some(digit) => println!("dtmf: {}", digit),
+                None => println!("dtmf: -"),
+            }
+            self.lines += 1;
+        }
+    }
+}
 
-                let data = &mut datas[0];
-                let n_channels = user_data.format.channels();
-                let n_samples = data.chunk().size() / (mem::size_of::<f32>() as u32);
+/// Redraw the meters of `printers` in place, one under the other, once any
+/// of them has new levels.
+fn draw(printers: &[RefCell<Printer>]) {
+    let mut printers: Vec<_> = printers.iter().map(RefCell::borrow_mut).collect();
+    if !printers.iter().any(|printer| printer.redraw) {
+        return;
+    }
+    let lines: usize = printers.iter().map(|printer| printer.lines).sum();
+    if lines > 0 {
+        print!("\x1B[{}A", lines);
+    }
+    for printer in &mut printers {
+        if let Some(stats) = printer.latest {
+            printer.print_levels(&stats);
+        }
+    }
+}
 
-                if let Some(samples) = data.data() {
-                    if user_data.cursor_move {
-                        print!("\x1B[{}A", n_channels + 1);
-                    }
-                    println!("captured {} samples", n_samples / n_channels);
-                    for c in 0..n_channels {
-                        let mut max: f32 = 0.0;
-                        for n in (c..n_samples).step_by(n_channels as usize) {
-                            let start = n as usize * mem::size_of::<f32>();
-                            let end = start + mem::size_of::<f32>();
-                            let chan = &samples[start..end];
-                            let f = f32::from_le_bytes(chan.try_into().unwrap());
-                            max = max.max(f.abs());
-                        }
+/// The printers of every stream, and what tells when the run is over.
+struct Outputs {
+    printers: Vec<RefCell<Printer>>,
+    /// Set by each stream once it has analyzed `--max-frames`.
+    finished: Vec<Arc<AtomicBool>>,
+    /// Set by each printer once it has printed, with `--dump-once`.
+    dumped: Vec<Rc<Cell<bool>>>,
+}
 
-                        let peak = ((max * 30.0) as usize).clamp(0, 39);
+impl Outputs {
+    /// Whether every stream has analyzed `--max-frames`.
+    fn finished(&self) -> bool {
+        self.finished
+            .iter()
+            .all(|finished| finished.load(Ordering::Acquire))
+    }
 
-                        println!(
-                            "channel {}: |{:>w1$}{:w2$}| peak:{}",
-                            c,
-                            "*",
-                            "",
-                            max,
-                            w1 = peak + 1,
-                            w2 = 40 - peak
-                        );
+    /// Whether every printer has printed with `--dump-once`; never without
+    /// it.
+    fn dumped(&self) -> bool {
+        !self.dumped.is_empty() && self.dumped.iter().all(|dumped| dumped.get())
+    }
+
+    /// Print what the streams have reported, leaving the meters as they
+    /// are.
+    fn drain(&self) {
+        for printer in &self.printers {
+            printer.borrow_mut().drain();
+        }
+    }
+
+    /// Print what the streams have reported and redraw the meters.
+    fn print(&self) {
+        self.drain();
+        draw(&self.printers);
+    }
+}
+
+/// Scheduling the data thread ended up with after `raise_thread_priority`.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(feature = "pipewire"), allow(dead_code))]
+enum Scheduling {
+    Fifo(libc::c_int),
+    RoundRobin(libc::c_int),
+    /// SCHED_OTHER at the given nice value, with the errno if raising it failed.
+    Other {
+        nice: libc::c_int,
+        error: Option<i32>,
+    },
+}
+
+impl std::fmt::Display for Scheduling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scheduling::Fifo(priority) => write!(f, "SCHED_FIFO priority {}", priority),
+            Scheduling::RoundRobin(priority) => write!(f, "SCHED_RR priority {}", priority),
+            Scheduling::Other { nice, error: None } => write!(f, "SCHED_OTHER nice {}", nice),
+            Scheduling::Other {
+                nice,
+                error: Some(errno),
+            } => write!(
+                f,
+                "SCHED_OTHER nice {} (could not raise: {})",
+                nice,
+                std::io::Error::from_raw_os_error(*errno)
+            ),
+        }
+    }
+}
+
+/// Short names of the SPA channel positions, indexed by
+/// `spa_audio_channel`, as PipeWire prints them.
+const CHANNEL_NAMES: [&str; 38] = [
+    "unknown", "NA", "MONO", "FL", "FR", "FC", "LFE", "SL", "SR", "FLC", "FRC", "RC", "RL", "RR",
+    "TC", "TFL", "TFC", "TFR", "TRL", "TRC", "TRR", "RLC", "RRC", "FLW", "FRW", "LFE2", "FLH",
+    "FCH", "FRH", "TFLC", "TFRC", "TSL", "TSR", "LLFE", "RLFE", "BC", "BLC", "BRC",
+];
+
+/// First of the `SPA_AUDIO_CHANNEL_AUX*` positions, for devices whose
+/// channels have no speaker position.
+const AUX_CHANNEL_START: u32 = 0x1000;
+const AUX_CHANNEL_END: u32 = 0x1fff;
+
+/// Name of an SPA channel position, e.g. "FL" or "AUX3".
+fn channel_name(position: u32) -> String {
+    match position {
+        AUX_CHANNEL_START..=AUX_CHANNEL_END => format!("AUX{}", position - AUX_CHANNEL_START),
+        _ => CHANNEL_NAMES
+            .get(position as usize)
+            .map_or_else(|| format!("{:#x}", position), |name| String::from(*name)),
+    }
+}
+
+/// `path` with `-<i>` added to the file stem, so each of several streams
+/// records to a file of its own.
+fn numbered_path(path: &Path, i: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, i, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, i),
+    };
+    path.with_file_name(name)
+}
+
+fn parse_duration_secs(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(seconds) if seconds > 0.0 && seconds.is_finite() => Ok(seconds),
+        _ => Err(format!(
+            "expected a positive number of seconds, got \"{}\"",
+            s
+        )),
+    }
+}
+
+fn parse_frequency(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(freq) if freq > 0.0 && freq.is_finite() => Ok(freq),
+        _ => Err(format!(
+            "expected a positive frequency in Hz, got \"{}\"",
+            s
+        )),
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List the audio sources and sinks known to PipeWire and exit
+    ListDevices {
+        #[clap(long, help = "Print one JSON object per node instead of a table")]
+        json: bool,
+    },
+    /// Run the analysis on generated test signals and check the results,
+    /// without touching PipeWire
+    SelfTest,
+}
+
+#[derive(Parser)]
+#[clap(name = "audio-capture", about = "Audio stream capture example")]
+struct Opt {
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(
+        long,
+        requires = "target",
+        help = "Print the sample formats, rates and channel layouts the --target node offers, then exit"
+    )]
+    list_formats: bool,
+    #[clap(
+        short,
+        long,
+        help = "The target node to connect to, by object id, serial or node name; repeat to capture several nodes at once"
+    )]
+    target: Vec<String>,
+    #[clap(
+        long,
+        help = "Per-channel gain trim applied before analysis, e.g. \"0:0dB,1:+3dB,2:-2dB\""
+    )]
+    channel_gains: Option<ChannelGains>,
+    #[clap(
+        long,
+        value_name = "GAIN,...",
+        value_parser = ChannelGains::parse_list,
+        conflicts_with = "channel_gains",
+        help = "Linear gain per channel before analysis, e.g. \"1.0,2.0,0.5\" or \"0dB,+6dB\"; the last one repeats for any further channels"
+    )]
+    gain: Option<ChannelGains>,
+    #[clap(short, long, help = "Don't print anything")]
+    quiet: bool,
+    #[clap(
+        long,
+        conflicts_with = "quiet",
+        help = "Print the first levels and exit, or exit with status 1 after --timeout-ms"
+    )]
+    dump_once: bool,
+    #[clap(
+        long,
+        value_name = "MS",
+        default_value_t = 5000,
+        help = "How long --dump-once waits for levels"
+    )]
+    timeout_ms: u64,
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        value_parser = parse_duration_secs,
+        help = "Stop capturing after this many seconds"
+    )]
+    run_for: Option<f64>,
+    #[clap(
+        long,
+        value_name = "N",
+        help = "Stop after analyzing exactly this many frames per channel, after any --warmup-frames"
+    )]
+    max_frames: Option<u64>,
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        help = "Analyze the first N frames per channel after connecting without reporting them, so startup transients settle in the filters and AGC; they don't count towards --max-frames"
+    )]
+    warmup_frames: u64,
+    #[clap(
+        long,
+        value_name = "SIGNAL",
+        conflicts_with = "target",
+        help = "Analyze a generated signal instead of connecting to PipeWire: a frequency in Hz or sine:HZ, sweep:F0-F1:SECONDS, white, pink or multitone:HZ,HZ,..."
+    )]
+    synthetic: Option<Signal>,
+    #[clap(
+        long,
+        help = "Estimate the fundamental frequency of a monophonic source"
+    )]
+    pitch: bool,
+    #[clap(
+        long,
+        value_name = "HZ",
+        value_parser = parse_frequency,
+        default_value_t = 440.0,
+        help = "Tuning of A4 for naming the --pitch note"
+    )]
+    a4: f64,
+    #[clap(
+        long,
+        help = "Measure momentary, short-term and integrated loudness per ITU-R BS.1770"
+    )]
+    lufs: bool,
+    #[clap(
+        long,
+        help = "Estimate inter-sample peaks by 4x oversampling, flagging them above 0 dBTP"
+    )]
+    true_peak: bool,
+    #[clap(
+        long,
+        value_name = "BOOL",
+        action = clap::ArgAction::Set,
+        default_value_t = true,
+        help = "Remove the DC offset of each channel with a 2 Hz high-pass before measuring levels"
+    )]
+    remove_dc: bool,
+    #[clap(
+        long,
+        help = "Capture what a sink is playing through its monitor ports instead of an input"
+    )]
+    monitor: bool,
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Also write the captured samples to this WAV file"
+    )]
+    record: Option<PathBuf>,
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["target", "synthetic", "monitor"],
+        help = "Analyze a WAV file, as fast as it decodes, instead of connecting to PipeWire"
+    )]
+    input_file: Option<PathBuf>,
+    #[clap(
+        long = "loop",
+        requires = "input_file",
+        help = "Start --input-file over from the beginning at its end, until interrupted"
+    )]
+    loop_input: bool,
+    #[clap(
+        long,
+        value_name = "N",
+        requires = "input_file",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Analyze --input-file this many times over, implies --loop"
+    )]
+    loop_count: Option<u64>,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        help = "How to print levels; status messages always go to stderr"
+    )]
+    format: OutputFormat,
+    #[clap(
+        long,
+        value_enum,
+        default_value_t = Backend::Pipewire,
+        help = "Capture from PipeWire, or from an input device through cpal; with cpal --target names the device"
+    )]
+    backend: Backend,
+    #[clap(
+        long,
+        value_name = "HZ,...",
+        value_delimiter = ',',
+        help = "Measure the magnitude at these frequencies with the Goertzel algorithm"
+    )]
+    goertzel: Vec<f32>,
+    #[clap(long, help = "Decode DTMF digits, measuring the eight DTMF tones")]
+    dtmf: bool,
+    #[clap(
+        long,
+        value_name = "MODE",
+        default_value = "average",
+        help = "Mix channels to mono for tones and pitch: average, sum (3 dB down per doubling of channels) or first"
+    )]
+    downmix: Downmix,
+    #[clap(
+        long,
+        value_name = "MODE",
+        default_value = "digital",
+        help = "How the channel meters respond: digital (sample peak), vu (RMS over 300 ms) or ppm (fast attack, slow release)"
+    )]
+    ballistics: Ballistics,
+    #[clap(
+        long,
+        value_name = "N",
+        conflicts_with = "downmix",
+        help = "Analyze tones and pitch on channel N only, counting from 0"
+    )]
+    channel: Option<usize>,
+    #[clap(
+        long,
+        value_name = "LEVEL",
+        default_value_t = 0.999,
+        help = "Absolute sample value counted as clipping"
+    )]
+    clip_threshold: f32,
+    #[clap(
+        long,
+        value_name = "DB",
+        allow_hyphen_values = true,
+        help = "Only show levels while the loudest channel's RMS is above this many dBFS"
+    )]
+    silence_threshold: Option<f32>,
+    #[clap(
+        long,
+        value_name = "DB",
+        help = "Flag buffers as transients, such as claps or pops, when a channel's peak is this many dB above its RMS level"
+    )]
+    crest_threshold: Option<f32>,
+    #[clap(
+        long,
+        help = "Normalize the level before analysis with a slow automatic gain control"
+    )]
+    agc: bool,
+    #[clap(
+        long,
+        value_name = "DB",
+        default_value_t = -20.0,
+        allow_hyphen_values = true,
+        help = "RMS level in dBFS --agc aims for"
+    )]
+    agc_target: f32,
+    #[clap(
+        long,
+        value_name = "MS",
+        default_value_t = 100,
+        help = "How fast --agc turns the gain down when the level rises"
+    )]
+    agc_attack_ms: u64,
+    #[clap(
+        long,
+        value_name = "MS",
+        default_value_t = 1000,
+        help = "How fast --agc turns the gain up when the level falls"
+    )]
+    agc_release_ms: u64,
+    #[clap(
+        long,
+        value_name = "DB",
+        default_value_t = 30.0,
+        help = "Most gain --agc applies, so silence isn't amplified into noise"
+    )]
+    agc_max_gain: f32,
+    #[clap(
+        long,
+        value_name = "DETECTOR",
+        help = "Show a smoothed envelope of the loudest channel's peak or rms"
+    )]
+    envelope: Option<EnvelopeDetector>,
+    #[clap(
+        long,
+        value_name = "MS",
+        default_value_t = 10,
+        help = "Time constant of the --envelope rising"
+    )]
+    envelope_attack_ms: u64,
+    #[clap(
+        long,
+        value_name = "MS",
+        default_value_t = 300,
+        help = "Time constant of the --envelope falling"
+    )]
+    envelope_release_ms: u64,
+    #[clap(
+        long,
+        value_name = "US",
+        help = "Estimate how far channel 1 lags channel 0, searching up to this many microseconds either way"
+    )]
+    max_delay_us: Option<u64>,
+    #[clap(
+        long,
+        value_name = "MS",
+        default_value_t = 500,
+        help = "How long the input has to stay below --silence-threshold to count as silent"
+    )]
+    silence_hold_ms: u64,
+    #[clap(
+        long,
+        value_name = "KIND:HZ",
+        help = "Filter the samples before analysis: lowpass:HZ, highpass:HZ or bandpass:LOW-HIGH; repeat to chain filters"
+    )]
+    filter: Vec<FilterSpec>,
+    #[clap(
+        long,
+        value_name = "K",
+        default_value_t = 0.0,
+        help = "Apply the pre-emphasis filter y[n] = x[n] - K*x[n-1] before analysis, e.g. 0.97 for speech; 0 disables it"
+    )]
+    preemphasis: f32,
+    #[clap(long, help = "Hold each channel's peak and let it fall off slowly")]
+    peak_hold: bool,
+    #[clap(
+        long,
+        value_name = "DB",
+        default_value_t = 20.0,
+        help = "How fast held peaks fall, for --peak-hold"
+    )]
+    decay_db_per_sec: f32,
+    #[clap(
+        long,
+        value_name = "FPS",
+        value_parser = parse_frequency,
+        help = "Gather buffers and analyze them together at most this many times a second"
+    )]
+    max_fps: Option<f64>,
+    #[clap(
+        long,
+        help = "Time each stage of the data thread and log averages and maxima every second"
+    )]
+    profile: bool,
+    #[clap(
+        long,
+        help = "Log everything about the negotiated format when the stream connects"
+    )]
+    verbose_format: bool,
+    #[clap(
+        long,
+        value_name = "FRAMES",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Ask for buffers of this many frames at 48 kHz; smaller means lower latency"
+    )]
+    quantum: Option<u32>,
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 5,
+        help = "Reconnect this many times, with backoff, when the stream or the server fails; 0 to fail at once"
+    )]
+    max_retries: u32,
+    #[clap(
+        long,
+        value_name = "PORT",
+        help = "Serve Prometheus metrics over HTTP on this port at /metrics"
+    )]
+    metrics_port: Option<u16>,
+    #[clap(
+        long,
+        value_name = "PATH",
+        help = "Read settings from a TOML file; options given here take precedence"
+    )]
+    config: Option<PathBuf>,
+}
+
+impl Opt {
+    /// Parse the command line and fill in whatever it leaves unset from
+    /// the `--config` file.
+    fn load() -> Result<Opt, Error> {
+        let matches = Opt::command().get_matches();
+        let mut opt = Opt::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+        let Some(path) = &opt.config else {
+            return Ok(opt);
+        };
+        let (config, keys) = Config::read(path).map_err(|err| Error::ReadConfig {
+            path: path.clone(),
+            err,
+        })?;
+        let invalid = |key, err: String| Error::InvalidConfig {
+            path: path.clone(),
+            key,
+            err,
+        };
+        let from_command_line =
+            |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        // options that conflict on the command line conflict in the file too
+        let command = Opt::command();
+        let given = |id: &str| from_command_line(id) || keys.iter().any(|key| key == id);
+        let describe = |arg: &clap::Arg| {
+            let id = arg.get_id().as_str();
+            match arg.get_long() {
+                Some(long) if from_command_line(id) => format!("--{}", long),
+                _ => format!("{} in {}", id.replace('_', "-"), path.display()),
+            }
+        };
+        for arg in command.get_arguments() {
+            let id = arg.get_id().as_str();
+            if !given(id) {
+                continue;
+            }
+            for other in command.get_arg_conflicts_with(arg) {
+                let other_id = other.get_id().as_str();
+                // clap has already turned down two on the command line
+                if given(other_id) && !(from_command_line(id) && from_command_line(other_id)) {
+                    return Err(Error::Conflict {
+                        option: describe(arg),
+                        other: describe(other),
+                    });
+                }
+            }
+        }
+
+        if opt.target.is_empty() {
+            opt.target = config.target.unwrap_or_default();
+        }
+        if opt.gain.is_none()
+            && let Some(gains) = config.gain
+        {
+            match ChannelGains::parse_list(&gains) {
+                Ok(gains) => opt.gain = Some(gains),
+                Err(err) => {
+                    return Err(invalid("gain", err.to_string()));
+                }
+            }
+        }
+        if opt.channel_gains.is_none()
+            && let Some(gains) = config.channel_gains
+        {
+            match gains.parse() {
+                Ok(gains) => opt.channel_gains = Some(gains),
+                Err(err) => {
+                    return Err(invalid("channel-gains", err.to_string()));
+                }
+            }
+        }
+        // flags can only be switched on from the command line
+        opt.quiet |= config.quiet.unwrap_or(false);
+        opt.pitch |= config.pitch.unwrap_or(false);
+        opt.lufs |= config.lufs.unwrap_or(false);
+        opt.true_peak |= config.true_peak.unwrap_or(false);
+        opt.agc |= config.agc.unwrap_or(false);
+        opt.monitor |= config.monitor.unwrap_or(false);
+        opt.dtmf |= config.dtmf.unwrap_or(false);
+        opt.peak_hold |= config.peak_hold.unwrap_or(false);
+        if opt.run_for.is_none()
+            && let Some(seconds) = config.run_for
+        {
+            match parse_duration_secs(&seconds.to_string()) {
+                Ok(seconds) => opt.run_for = Some(seconds),
+                Err(err) => {
+                    return Err(invalid("run-for", err.to_string()));
+                }
+            }
+        }
+        opt.record = opt.record.or(config.record);
+        opt.max_frames = opt.max_frames.or(config.max_frames);
+        if !from_command_line("warmup_frames") {
+            opt.warmup_frames = config.warmup_frames.unwrap_or(opt.warmup_frames);
+        }
+        if opt.quantum.is_none() && config.quantum == Some(0) {
+            return Err(invalid("quantum", "must be at least 1".to_owned()));
+        }
+        opt.quantum = opt.quantum.or(config.quantum);
+        opt.metrics_port = opt.metrics_port.or(config.metrics_port);
+        opt.silence_threshold = opt.silence_threshold.or(config.silence_threshold);
+        opt.crest_threshold = opt.crest_threshold.or(config.crest_threshold);
+        if !from_command_line("silence_hold_ms") {
+            opt.silence_hold_ms = config.silence_hold_ms.unwrap_or(opt.silence_hold_ms);
+        }
+        if !from_command_line("a4") {
+            if let Some(a4) = config.a4
+                && parse_frequency(&a4.to_string()).is_err()
+            {
+                return Err(invalid("a4", "must be a positive frequency".to_owned()));
+            }
+            opt.a4 = config.a4.unwrap_or(opt.a4);
+        }
+        if !from_command_line("downmix")
+            && let Some(downmix) = config.downmix
+        {
+            match downmix.parse() {
+                Ok(downmix) => opt.downmix = downmix,
+                Err(err) => {
+                    return Err(invalid("downmix", err.to_string()));
+                }
+            }
+        }
+        if !from_command_line("ballistics")
+            && let Some(ballistics) = config.ballistics
+        {
+            match ballistics.parse() {
+                Ok(ballistics) => opt.ballistics = ballistics,
+                Err(err) => {
+                    return Err(invalid("ballistics", err.to_string()));
+                }
+            }
+        }
+        if !from_command_line("envelope")
+            && let Some(envelope) = config.envelope
+        {
+            match envelope.parse() {
+                Ok(envelope) => opt.envelope = Some(envelope),
+                Err(err) => {
+                    return Err(invalid("envelope", err.to_string()));
+                }
+            }
+        }
+        opt.channel = opt.channel.or(config.channel);
+        if opt.goertzel.is_empty() {
+            opt.goertzel = config.goertzel.unwrap_or_default();
+        }
+        if !from_command_line("remove_dc") {
+            opt.remove_dc = config.remove_dc.unwrap_or(opt.remove_dc);
+        }
+        if opt.filter.is_empty() {
+            for filter in config.filter.unwrap_or_default() {
+                match filter.parse() {
+                    Ok(filter) => opt.filter.push(filter),
+                    Err(err) => {
+                        return Err(invalid("filter", err.to_string()));
                     }
-                    user_data.cursor_move = true;
                 }
             }
+        }
+        if !from_command_line("preemphasis") {
+            opt.preemphasis = config.preemphasis.unwrap_or(opt.preemphasis);
+        }
+        if !from_command_line("decay_db_per_sec") {
+            opt.decay_db_per_sec = config.decay_db_per_sec.unwrap_or(opt.decay_db_per_sec);
+        }
+        if !from_command_line("agc_target") {
+            opt.agc_target = config.agc_target.unwrap_or(opt.agc_target);
+        }
+        if !from_command_line("agc_attack_ms") {
+            opt.agc_attack_ms = config.agc_attack_ms.unwrap_or(opt.agc_attack_ms);
+        }
+        if !from_command_line("agc_release_ms") {
+            opt.agc_release_ms = config.agc_release_ms.unwrap_or(opt.agc_release_ms);
+        }
+        if !from_command_line("agc_max_gain") {
+            opt.agc_max_gain = config.agc_max_gain.unwrap_or(opt.agc_max_gain);
+        }
+        if !from_command_line("envelope_attack_ms") {
+            opt.envelope_attack_ms = config.envelope_attack_ms.unwrap_or(opt.envelope_attack_ms);
+        }
+        if !from_command_line("envelope_release_ms") {
+            opt.envelope_release_ms = config
+                .envelope_release_ms
+                .unwrap_or(opt.envelope_release_ms);
+        }
+        if !from_command_line("max_delay_us") {
+            opt.max_delay_us = opt.max_delay_us.or(config.max_delay_us);
+        }
+        opt.verbose_format |= config.verbose_format.unwrap_or(false);
+        opt.profile |= config.profile.unwrap_or(false);
+        if !from_command_line("max_fps") {
+            if let Some(fps) = config.max_fps
+                && parse_frequency(&fps.to_string()).is_err()
+            {
+                return Err(invalid("max-fps", "must be positive".to_owned()));
+            }
+            opt.max_fps = opt.max_fps.or(config.max_fps);
+        }
+        if !from_command_line("max_retries") {
+            opt.max_retries = config.max_retries.unwrap_or(opt.max_retries);
+        }
+        if !from_command_line("clip_threshold") {
+            opt.clip_threshold = config.clip_threshold.unwrap_or(opt.clip_threshold);
+        }
+        if !from_command_line("format") {
+            opt.format = config.format.unwrap_or(opt.format);
+        }
+        if !from_command_line("backend") {
+            opt.backend = config.backend.unwrap_or(opt.backend);
+        }
+        Ok(opt)
+    }
+
+    /// How long `--run-for`, or the `--timeout-ms` with `--dump-once`,
+    /// gives the run, whichever ends it first.
+    fn deadline(&self) -> Option<Duration> {
+        let run_for = self.run_for.map(Duration::from_secs_f64);
+        let dump_timeout = self
+            .dump_once
+            .then(|| Duration::from_millis(self.timeout_ms));
+        run_for.into_iter().chain(dump_timeout).min()
+    }
+}
+
+/// Settings from a `--config` file. The keys are named after the long
+/// options they stand in for, e.g.
+///
+/// ```toml
+/// target = ["alsa_input.usb-mic"]
+/// channel-gains = "0:0dB,1:+3dB"
+/// remove-dc = false
+/// format = "json"
+/// ```
+#[derive(Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    target: Option<Vec<String>>,
+    channel_gains: Option<String>,
+    gain: Option<String>,
+    quiet: Option<bool>,
+    run_for: Option<f64>,
+    max_frames: Option<u64>,
+    warmup_frames: Option<u64>,
+    pitch: Option<bool>,
+    a4: Option<f64>,
+    lufs: Option<bool>,
+    true_peak: Option<bool>,
+    remove_dc: Option<bool>,
+    monitor: Option<bool>,
+    record: Option<PathBuf>,
+    format: Option<OutputFormat>,
+    backend: Option<Backend>,
+    goertzel: Option<Vec<f32>>,
+    dtmf: Option<bool>,
+    downmix: Option<String>,
+    ballistics: Option<String>,
+    channel: Option<usize>,
+    clip_threshold: Option<f32>,
+    metrics_port: Option<u16>,
+    max_retries: Option<u32>,
+    quantum: Option<u32>,
+    silence_threshold: Option<f32>,
+    silence_hold_ms: Option<u64>,
+    crest_threshold: Option<f32>,
+    agc: Option<bool>,
+    agc_target: Option<f32>,
+    agc_attack_ms: Option<u64>,
+    agc_release_ms: Option<u64>,
+    agc_max_gain: Option<f32>,
+    envelope: Option<String>,
+    envelope_attack_ms: Option<u64>,
+    envelope_release_ms: Option<u64>,
+    max_delay_us: Option<u64>,
+    verbose_format: Option<bool>,
+    profile: Option<bool>,
+    max_fps: Option<f64>,
+    filter: Option<Vec<String>>,
+    preemphasis: Option<f32>,
+    peak_hold: Option<bool>,
+    decay_db_per_sec: Option<f32>,
+}
+
+impl Config {
+    /// Read the file at `path`, along with the ids of the options it sets.
+    fn read(path: &Path) -> Result<(Config, Vec<String>), Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(path)?;
+        let config = toml::from_str(&text)?;
+        let table: toml::Table = toml::from_str(&text)?;
+        let keys = table
+            .into_iter()
+            // a flag set to false is the same as leaving it out
+            .filter(|(_, value)| value.as_bool() != Some(false))
+            .map(|(key, _)| key.replace('-', "_"))
+            .collect();
+        Ok((config, keys))
+    }
+}
+
+/// Send status messages to stderr, filtered by `RUST_LOG` if it is set.
+/// Otherwise `--quiet` keeps everything short of warnings to itself.
+fn init_logging(quiet: bool) {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(if quiet { "warn" } else { "info" }));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+/// Why the monitor couldn't start, or stopped short.
+#[derive(Debug)]
+enum Error {
+    #[cfg(feature = "pipewire")]
+    PipeWire(pipewire::Error),
+    /// Something was asked for that this build was made without.
+    #[cfg_attr(all(feature = "pipewire", feature = "cpal"), allow(dead_code))]
+    MissingFeature {
+        what: &'static str,
+        feature: &'static str,
+    },
+    /// The `--config` file couldn't be read or parsed.
+    ReadConfig {
+        path: PathBuf,
+        err: Box<dyn std::error::Error>,
+    },
+    /// A `--config` setting has a value its option wouldn't take.
+    InvalidConfig {
+        path: PathBuf,
+        key: &'static str,
+        err: String,
+    },
+    /// Two options were given that can't be used together, between the
+    /// command line and the `--config` file.
+    Conflict { option: String, other: String },
+    /// More `--goertzel` and `--dtmf` tones than `MAX_TONES`.
+    TooManyTones,
+    /// This input only takes a single `--target`.
+    SingleTarget(&'static str),
+    /// The analysis can't be set up with these options for the format.
+    Configure(ConfigError),
+    /// The `--record` file couldn't be created.
+    Record(hound::Error),
+    /// A format, sample format or channel count the analysis can't take.
+    UnsupportedFormat(String),
+    /// The negotiated format couldn't be parsed.
+    #[cfg(feature = "pipewire")]
+    ParseFormat(pipewire::spa::utils::result::Error),
+    /// The format params offered to the server couldn't be built.
+    #[cfg(feature = "pipewire")]
+    Params(String),
+    /// The `--metrics-port` listener couldn't be bound.
+    Metrics { port: u16, err: std::io::Error },
+    /// The `--input-file` couldn't be opened.
+    InputFile { path: PathBuf, err: hound::Error },
+    /// The cpal input device couldn't be opened, or stopped delivering.
+    #[cfg(feature = "cpal")]
+    InputDevice(rust_audio_monitor::CpalError),
+    /// No node matches this `--target`.
+    #[cfg(feature = "pipewire")]
+    TargetNotFound(String),
+    /// A stream or the connection failed, and `retries` reconnect attempts
+    /// didn't bring it back for good.
+    #[cfg(feature = "pipewire")]
+    StreamFailed { retries: u32 },
+    /// Nothing was printed within the `--timeout-ms` of `--dump-once`.
+    NoLevels { timeout_ms: u64 },
+    /// A `self-test` check failed; the checks print which.
+    SelfTestFailed,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(feature = "pipewire")]
+            Error::PipeWire(err) => write!(f, "{}", err),
+            Error::MissingFeature { what, feature } => {
+                write!(f, "{} needs a build with the {} feature", what, feature)
+            }
+            Error::ReadConfig { path, err } => {
+                write!(f, "failed to read {}: {}", path.display(), err)
+            }
+            Error::InvalidConfig { path, key, err } => {
+                write!(f, "invalid {} in {}: {}", key, path.display(), err)
+            }
+            Error::Conflict { option, other } => {
+                write!(f, "{} can't be used with {}", option, other)
+            }
+            Error::TooManyTones => {
+                write!(f, "at most {} tone frequencies can be measured", MAX_TONES)
+            }
+            Error::SingleTarget(option) => write!(f, "{} takes a single --target", option),
+            Error::Configure(err) => write!(f, "invalid configuration: {}", err),
+            Error::Record(err) => write!(f, "failed to start recording: {}", err),
+            Error::UnsupportedFormat(format) => write!(f, "unsupported {}", format),
+            #[cfg(feature = "pipewire")]
+            Error::ParseFormat(err) => {
+                write!(f, "failed to parse the negotiated format: {}", err)
+            }
+            #[cfg(feature = "pipewire")]
+            Error::Params(err) => write!(f, "failed to build the format params: {}", err),
+            Error::Metrics { port, err } => {
+                write!(f, "failed to serve metrics on port {}: {}", port, err)
+            }
+            Error::InputFile { path, err } => {
+                write!(f, "failed to open {}: {}", path.display(), err)
+            }
+            #[cfg(feature = "cpal")]
+            Error::InputDevice(err @ rust_audio_monitor::CpalError::Stalled) => {
+                write!(f, "{}", err)
+            }
+            #[cfg(feature = "cpal")]
+            Error::InputDevice(err) => write!(f, "failed to open input device: {}", err),
+            #[cfg(feature = "pipewire")]
+            Error::TargetNotFound(target) => write!(f, "target node \"{}\" not found", target),
+            #[cfg(feature = "pipewire")]
+            Error::StreamFailed { retries: 0 } => write!(f, "capture failed"),
+            #[cfg(feature = "pipewire")]
+            Error::StreamFailed { retries } => {
+                write!(
+                    f,
+                    "capture failed, giving up after {} reconnect attempts",
+                    retries
+                )
+            }
+            Error::NoLevels { timeout_ms } => write!(f, "no levels within {} ms", timeout_ms),
+            Error::SelfTestFailed => write!(f, "self-test failed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(feature = "pipewire")]
+impl From<pipewire::Error> for Error {
+    fn from(err: pipewire::Error) -> Self {
+        Error::PipeWire(err)
+    }
+}
+
+pub fn main() {
+    let opt = match Opt::load() {
+        Ok(opt) => opt,
+        Err(err) => {
+            // logging isn't set up yet, so report it the way clap does
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    init_logging(opt.quiet);
+    if let Err(err) = run(opt) {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+/// Everything after the command line, with setup failures and whatever
+/// stops the capture short returned rather than exiting. Stream errors that
+/// are reconnected from are only logged.
+fn run(opt: Opt) -> Result<(), Error> {
+    if let Some(Command::SelfTest) = opt.command {
+        return if self_test() {
+            Ok(())
+        } else {
+            Err(Error::SelfTestFailed)
+        };
+    }
+    if let Some(Command::ListDevices { json }) = opt.command {
+        return capture::list_devices(json);
+    }
+    if opt.list_formats {
+        return capture::list_formats(&opt.target);
+    }
+
+    let mut tones = opt.goertzel.clone();
+    if opt.dtmf {
+        tones.extend(DTMF_ROWS.into_iter().chain(DTMF_COLUMNS));
+    }
+    if tones.len() > MAX_TONES {
+        return Err(Error::TooManyTones);
+    }
+
+    /* Several targets are only captured from PipeWire, and would all
+     * report into the same gauges. */
+    if opt.target.len() > 1 {
+        let conflict = if opt.synthetic.is_some() {
+            Some("--synthetic")
+        } else if opt.input_file.is_some() {
+            Some("--input-file")
+        } else if opt.backend == Backend::Cpal {
+            Some("--backend cpal")
+        } else if opt.metrics_port.is_some() {
+            Some("--metrics-port")
+        } else {
+            None
+        };
+        if let Some(conflict) = conflict {
+            return Err(Error::SingleTarget(conflict));
+        }
+    }
+
+    let metrics = match opt.metrics_port {
+        Some(port) => {
+            let metrics = Arc::new(Mutex::new(Metrics::default()));
+            if let Err(err) = serve_metrics(("0.0.0.0", port), metrics.clone()) {
+                return Err(Error::Metrics { port, err });
+            }
+            Some(metrics)
+        }
+        None => None,
+    };
+
+    /* One stream per --target, each with its own processing, queue and
+     * printer, or a single one for the default node. */
+    let labels: Vec<Option<&str>> = match opt.target.len() {
+        0 | 1 => vec![None],
+        _ => opt
+            .target
+            .iter()
+            .map(|target| Some(target.as_str()))
+            .collect(),
+    };
+    let mut streams = Vec::new();
+    let mut printers = Vec::new();
+    let mut finished = Vec::new();
+    let mut dumped = Vec::new();
+    for (i, &label) in labels.iter().enumerate() {
+        let (producer, consumer) = rtrb::RingBuffer::new(EVENT_QUEUE_SIZE);
+        let stream_finished = Arc::new(AtomicBool::new(false));
+        let stream_dumped = opt.dump_once.then(|| Rc::new(Cell::new(false)));
+        let printer = (!opt.quiet || metrics.is_some()).then(|| {
+            RefCell::new(Printer {
+                label: label.map(String::from),
+                events: consumer,
+                quiet: opt.quiet,
+                metrics: metrics.clone(),
+                format: opt.format,
+                lines: 0,
+                latest: None,
+                redraw: false,
+                show_pitch: opt.pitch,
+                show_delay: opt.max_delay_us.is_some(),
+                show_transients: opt.crest_threshold.is_some(),
+                ballistics: opt.ballistics,
+                a4: opt.a4 as f32,
+                channel: opt.channel,
+                tones: tones.clone(),
+                dtmf: opt.dtmf,
+                positions: None,
+                last_seq: None,
+                dropped: 0,
+                xruns: 0,
+                non_finite: 0,
+                silent: false,
+                dumped: stream_dumped.clone(),
+                max_fps: opt.max_fps,
+            })
+        });
+
+        let data = UserData {
+            analyzer: Analyzer::new(AnalyzerConfig {
+                processor: ProcessorConfig {
+                    channel_gains: opt
+                        .gain
+                        .clone()
+                        .or_else(|| opt.channel_gains.clone())
+                        .unwrap_or_default(),
+                    pitch: opt.pitch,
+                    loudness: opt.lufs,
+                    true_peak: opt.true_peak,
+                    remove_dc: opt.remove_dc,
+                    tones: tones.clone(),
+                    downmix: opt.channel.map_or(opt.downmix, Downmix::Channel),
+                    ballistics: opt.ballistics,
+                    clip_threshold: opt.clip_threshold,
+                    silence_threshold_db: opt.silence_threshold,
+                    silence_hold: Duration::from_millis(opt.silence_hold_ms),
+                    peak_hold_decay_db: opt.peak_hold.then_some(opt.decay_db_per_sec),
+                    envelope: opt.envelope,
+                    envelope_attack: Duration::from_millis(opt.envelope_attack_ms),
+                    envelope_release: Duration::from_millis(opt.envelope_release_ms),
+                    max_delay: opt.max_delay_us.map(Duration::from_micros),
+                    crest_threshold_db: opt.crest_threshold,
+                },
+                filters: opt.filter.clone(),
+                preemphasis: opt.preemphasis,
+                agc: opt.agc.then(|| {
+                    AutoGain::new(
+                        opt.agc_target,
+                        Duration::from_millis(opt.agc_attack_ms).as_secs_f32(),
+                        Duration::from_millis(opt.agc_release_ms).as_secs_f32(),
+                        opt.agc_max_gain,
+                    )
+                }),
+                max_fps: opt.max_fps,
+                warmup: opt.warmup_frames,
+                max_frames: opt.max_frames,
+                profile: opt.profile,
+            }),
+            samples: Vec::new(),
+            events: printer.is_some().then_some(producer),
+            seq: 0,
+            record_path: opt.record.as_deref().map(|path| match label {
+                Some(_) => numbered_path(path, i),
+                None => path.to_owned(),
+            }),
+            finished: stream_finished.clone(),
+        };
+        streams.push(data);
+        printers.extend(printer);
+        finished.push(stream_finished);
+        dumped.extend(stream_dumped);
+    }
+
+    let outputs = Rc::new(Outputs {
+        printers,
+        finished,
+        dumped,
+    });
+
+    /* Files, the synthetic signal and cpal devices are read on this thread;
+     * PipeWire streams push their buffers from the data thread. */
+    if opt.synthetic.is_some() || opt.input_file.is_some() || opt.backend == Backend::Cpal {
+        let mut driver = Driver::new(&outputs, opt.deadline());
+        let data = streams.remove(0);
+        if let Some(signal) = &opt.synthetic {
+            synthesize(&mut driver, data, signal)?;
+        } else if let Some(path) = &opt.input_file {
+            let passes = match (opt.loop_count, opt.loop_input) {
+                (Some(count), _) => Some(count),
+                (None, true) => None,
+                (None, false) => Some(1),
+            };
+            analyze_file(&mut driver, data, path, passes)?;
+        } else {
+            let device = opt.target.first().map(String::as_str);
+            capture_cpal(&mut driver, device, data)?;
+        }
+        // whatever came in since the last redraw
+        outputs.print();
+    } else {
+        capture::run(&opt, streams, outputs.clone())?;
+    }
+
+    info!("capture stopped");
+
+    if outputs.dumped.iter().any(|dumped| !dumped.get()) {
+        return Err(Error::NoLevels {
+            timeout_ms: opt.timeout_ms,
+        });
+    }
+
+    Ok(())
+}
+
+/// Stand-ins for the PipeWire side in builds without it.
+#[cfg(not(feature = "pipewire"))]
+mod capture {
+    use crate::{Error, Opt, Outputs, UserData};
+    use std::rc::Rc;
+
+    const MISSING: Error = Error::MissingFeature {
+        what: "PipeWire capture",
+        feature: "pipewire",
+    };
+
+    pub fn run(_opt: &Opt, _streams: Vec<UserData>, _outputs: Rc<Outputs>) -> Result<(), Error> {
+        Err(MISSING)
+    }
+
+    pub fn list_devices(_json: bool) -> Result<(), Error> {
+        Err(MISSING)
+    }
+
+    pub fn list_formats(_targets: &[String]) -> Result<(), Error> {
+        Err(MISSING)
+    }
+}
+
+/// Set by the SIGINT and SIGTERM handlers a `Driver` installs.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+/// How a `Driver` run ended.
+enum Ended {
+    /// The source ran out after this many frames.
+    RanOut(usize),
+    /// Ctrl-C, the deadline or the stream being done came first.
+    Stopped,
+}
+
+/// Analyzes an [`AudioSource`] on the main thread, standing in for what the
+/// PipeWire main loop's signal handlers and timers do for captured
+/// streams: printing as it goes, and stopping on Ctrl-C or SIGTERM, at the
+/// deadline or once the streams are done.
+struct Driver<'a> {
+    outputs: &'a Outputs,
+    deadline: Option<Instant>,
+    /// When the meters were last redrawn.
+    printed: Instant,
+}
+
+impl<'a> Driver<'a> {
+    /// Start the clock on `deadline`, and catch Ctrl-C and SIGTERM so the
+    /// recording still gets finished.
+    fn new(outputs: &'a Outputs, deadline: Option<Duration>) -> Self {
+        let handler = interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        unsafe {
+            libc::signal(libc::SIGINT, handler);
+            libc::signal(libc::SIGTERM, handler);
+        }
+        let now = Instant::now();
+        Driver {
+            outputs,
+            deadline: deadline.map(|deadline| now + deadline),
+            printed: now,
+        }
+    }
+
+    fn stopped(&self) -> bool {
+        INTERRUPTED.load(Ordering::Relaxed)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            || self.outputs.finished()
+            || self.outputs.dumped()
+    }
+
+    /// Analyze `source` into `data`, configured for its format, until it
+    /// runs out or the run is stopped. `paced` keeps it to real time, for a
+    /// generated signal standing in for a live one; otherwise it is
+    /// analyzed as fast as it reads.
+    fn run<S: AudioSource>(
+        &mut self,
+        source: &mut S,
+        data: &mut UserData,
+        paced: bool,
+    ) -> Result<Ended, S::Error> {
+        let (n_channels, rate) = (source.n_channels(), source.rate());
+        let started = Instant::now();
+        let mut frames = 0;
+        while !self.stopped() {
+            // a live source comes back empty after a while, to check again
+            let samples = &mut data.samples[..PLAY_FRAMES * n_channels];
+            let Some(n_frames) = source.read(samples, PRINT_INTERVAL)? else {
+                return Ok(Ended::RanOut(frames));
+            };
+            if n_frames > 0 {
+                data.analyze(n_frames, n_channels);
+                frames += n_frames;
+            }
+
+            // drained every buffer, so the queue can't overflow
+            if self.printed.elapsed() >= PRINT_INTERVAL {
+                self.outputs.print();
+                self.printed = Instant::now();
+            } else {
+                self.outputs.drain();
+            }
+            if paced {
+                let due = started + Duration::from_secs_f64(frames as f64 / rate as f64);
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+            }
+        }
+        Ok(Ended::Stopped)
+    }
+}
+
+/// Get `data` ready for the format `source` reads in.
+fn prepare(data: &mut UserData, source: &impl AudioSource) -> Result<(), Error> {
+    let (n_channels, rate) = (source.n_channels(), source.rate());
+    data.configure(n_channels, rate, PLAY_FRAMES)
+        .map_err(Error::Configure)?;
+    data.start_recording(n_channels, rate)
+        .map_err(Error::Record)
+}
+
+/// The `--synthetic` signal, in the format a capture stream would
+/// negotiate.
+struct Synthetic(SignalGenerator);
+
+impl AudioSource for Synthetic {
+    type Error = Infallible;
+
+    fn n_channels(&self) -> usize {
+        SYNTHETIC_CHANNELS
+    }
+
+    fn rate(&self) -> u32 {
+        SYNTHETIC_RATE
+    }
+
+    fn read(
+        &mut self,
+        samples: &mut [f32],
+        _timeout: Duration,
+    ) -> Result<Option<usize>, Infallible> {
+        self.0.fill(samples, SYNTHETIC_CHANNELS);
+        Ok(Some(samples.len() / SYNTHETIC_CHANNELS))
+    }
+}
+
+/// Analyze the `--synthetic` signal in real time, until the run is
+/// stopped. No PipeWire connection is made.
+fn synthesize(driver: &mut Driver, mut data: UserData, signal: &Signal) -> Result<(), Error> {
+    info!(
+        "synthesizing {} rate:{} channels:{}",
+        signal, SYNTHETIC_RATE, SYNTHETIC_CHANNELS
+    );
+    let mut source = Synthetic(SignalGenerator::new(signal.clone(), SYNTHETIC_RATE, 0.5));
+    prepare(&mut data, &source)?;
+    let Ok(_) = driver.run(&mut source, &mut data, true);
+    Ok(())
+}
+
+/// Capture from the input device called `device`, or the default one,
+/// through cpal.
+#[cfg(feature = "cpal")]
+fn capture_cpal(
+    driver: &mut Driver,
+    device: Option<&str>,
+    mut data: UserData,
+) -> Result<(), Error> {
+    let mut source = CpalSource::open(device).map_err(Error::InputDevice)?;
+    let (n_channels, rate) = (source.n_channels(), source.rate());
+    if n_channels == 0 || n_channels > MAX_CHANNELS || rate == 0 {
+        return Err(Error::UnsupportedFormat(format!(
+            "device format rate:{} channels:{}",
+            rate, n_channels
+        )));
+    }
+    info!(
+        "capturing {} through cpal rate:{} channels:{}",
+        device.unwrap_or("the default input device"),
+        rate,
+        n_channels
+    );
+    prepare(&mut data, &source)?;
+    driver
+        .run(&mut source, &mut data, false)
+        .map_err(Error::InputDevice)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "cpal"))]
+fn capture_cpal(_driver: &mut Driver, _device: Option<&str>, _data: UserData) -> Result<(), Error> {
+    Err(Error::MissingFeature {
+        what: "--backend cpal",
+        feature: "cpal",
+    })
+}
+
+/// Analyze the WAV file at `path`, `passes` times over or until
+/// interrupted if `None`.
+///
+/// The file is read and analyzed as fast as it decodes rather than in real
+/// time, printing every buffer as it goes.
+fn analyze_file(
+    driver: &mut Driver,
+    mut data: UserData,
+    path: &Path,
+    passes: Option<u64>,
+) -> Result<(), Error> {
+    let open = || {
+        WavSource::open(path).map_err(|err| Error::InputFile {
+            path: path.to_owned(),
+            err,
         })
-        .register()?;
-
-    /* Make one parameter with the supported formats. The SPA_PARAM_EnumFormat
-     * id means that this is a format enumeration (of 1 value).
-     * We leave the channels and rate empty to accept the native graph
-     * rate and channels. */
-    let mut audio_info = spa::param::audio::AudioInfoRaw::new();
-    audio_info.set_format(spa::param::audio::AudioFormat::F32LE);
-    let obj = pw::spa::pod::Object {
-        type_: pw::spa::utils::SpaTypes::ObjectParamFormat.as_raw(),
-        id: pw::spa::param::ParamType::EnumFormat.as_raw(),
-        properties: audio_info.into(),
     };
-    let values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
-        std::io::Cursor::new(Vec::new()),
-        &pw::spa::pod::Value::Object(obj),
-    )
-    .unwrap()
-    .0
-    .into_inner();
-
-    let mut params = [Pod::from_bytes(&values).unwrap()];
-
-    /* Now connect this stream. We ask that our process function is
-     * called in a realtime thread. */
-    stream.connect(
-        spa::utils::Direction::Input,
-        None,
-        pw::stream::StreamFlags::AUTOCONNECT
-            | pw::stream::StreamFlags::MAP_BUFFERS
-            | pw::stream::StreamFlags::RT_PROCESS,
-        &mut params,
-    )?;
-
-    // and wait while we let things run
-    mainloop.run();
+    let mut source = open()?;
+    let (n_channels, rate) = (source.n_channels(), source.rate());
+    if n_channels == 0 || n_channels > MAX_CHANNELS || rate == 0 {
+        return Err(Error::UnsupportedFormat(format!(
+            "file format rate:{} channels:{}",
+            rate, n_channels
+        )));
+    }
+    info!(
+        "analyzing {} rate:{} channels:{}",
+        path.display(),
+        rate,
+        n_channels
+    );
+    prepare(&mut data, &source)?;
 
+    let mut pass = 1;
+    loop {
+        let frames = match driver.run(&mut source, &mut data, false) {
+            Ok(Ended::RanOut(frames)) => frames,
+            Ok(Ended::Stopped) => break,
+            Err(err) => {
+                error!("failed to read {}: {}", path.display(), err);
+                break;
+            }
+        };
+        // an empty file would otherwise be reopened forever
+        if frames == 0 || passes.is_some_and(|passes| pass >= passes) {
+            break;
+        }
+        // rewind by reopening, the decoder only reads forwards
+        source = match open() {
+            Ok(source) => source,
+            Err(err) => {
+                error!("failed to reopen {}: {}", path.display(), err);
+                break;
+            }
+        };
+        if let Err(err) = data.analyzer.restart() {
+            error!("invalid configuration: {}", err);
+            break;
+        }
+        pass += 1;
+        debug!("starting pass {} over {}", pass, path.display());
+    }
     Ok(())
 }
+
+/// Frames of each test signal `self-test` analyzes.
+const SELF_TEST_FRAMES: usize = 8192;
+
+/// Rate of the `self-test` signals.
+const SELF_TEST_RATE: u32 = 48000;
+
+/// Mono mix of sines at each `(freq, amplitude)`, for `self-test`.
+fn test_signal(tones: &[(f64, f32)]) -> Vec<f32> {
+    let mut mix = vec![0.0; SELF_TEST_FRAMES];
+    let mut tone = vec![0.0; SELF_TEST_FRAMES];
+    for &(freq, amplitude) in tones {
+        SignalGenerator::new(Signal::Sine(freq), SELF_TEST_RATE, amplitude).fill(&mut tone, 1);
+        for (mix, sample) in mix.iter_mut().zip(&tone) {
+            *mix += sample;
+        }
+    }
+    mix
+}
+
+/// Check the analysis against signals with known answers, printing a line
+/// per check. Returns whether all of them passed.
+fn self_test() -> bool {
+    let mut checks: Vec<(&str, Result<(), String>)> = Vec::new();
+    let close = |name: &str, got: f32, want: f32, tolerance: f32| {
+        if (got - want).abs() <= tolerance {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} is {}, expected {} ± {}",
+                name, got, want, tolerance
+            ))
+        }
+    };
+
+    let mut signal = test_signal(&[(1000.0, 0.5)]);
+    let mut processor = AudioProcessor::new(ProcessorConfig {
+        // the DC blocker overshoots by about 0.2% while it settles
+        remove_dc: false,
+        ..Default::default()
+    });
+    let level = processor.process_frame(&mut signal, 1, SELF_TEST_RATE)[0];
+    checks.push((
+        "levels",
+        close("peak", level.peak, 0.5, 0.001).and(close(
+            "rms",
+            level.rms,
+            0.5 * std::f32::consts::FRAC_1_SQRT_2,
+            0.001,
+        )),
+    ));
+
+    let freqs = [440.0, 1000.0, 3000.0, 2000.0];
+    let mut signal = test_signal(&[(440.0, 0.4), (1000.0, 0.2), (3000.0, 0.1)]);
+    let mut processor = AudioProcessor::new(ProcessorConfig {
+        tones: freqs.to_vec(),
+        ..Default::default()
+    });
+    processor.process_frame(&mut signal, 1, SELF_TEST_RATE);
+    let magnitudes = processor.tone_magnitudes();
+    checks.push((
+        "tones",
+        close("440 Hz", magnitudes[0], 0.4, 0.02)
+            .and(close("1000 Hz", magnitudes[1], 0.2, 0.02))
+            .and(close("3000 Hz", magnitudes[2], 0.1, 0.02))
+            .and(close("2000 Hz", magnitudes[3], 0.0, 0.02)),
+    ));
+
+    let mut signal = test_signal(&[(440.0, 0.5)]);
+    let mut processor = AudioProcessor::new(ProcessorConfig {
+        pitch: true,
+        ..Default::default()
+    });
+    processor.process_frame(&mut signal, 1, SELF_TEST_RATE);
+    checks.push((
+        "pitch",
+        match processor.pitch() {
+            Some(pitch) => {
+                close("pitch", pitch.freq, 440.0, 1.0).and_then(|()| {
+                    match Note::nearest(pitch.freq, 440.0) {
+                        Some(note) if (note.name, note.octave) == ("A", 4) => Ok(()),
+                        note => Err(format!("note is {:?}, expected A4", note)),
+                    }
+                })
+            }
+            None => Err(String::from("no pitch detected")),
+        },
+    ));
+
+    let mut signal = test_signal(&[(770.0, 0.3), (1336.0, 0.3)]);
+    let dtmf: Vec<f32> = DTMF_ROWS.iter().chain(&DTMF_COLUMNS).copied().collect();
+    let mut processor = AudioProcessor::new(ProcessorConfig {
+        tones: dtmf.clone(),
+        ..Default::default()
+    });
+    processor.process_frame(&mut signal, 1, SELF_TEST_RATE);
+    checks.push((
+        "dtmf",
+        match dtmf_digit(&dtmf, processor.tone_magnitudes()) {
+            Some('5') => Ok(()),
+            digit => Err(format!("digit is {:?}, expected '5'", digit)),
+        },
+    ));
+
+    let mut signal = test_signal(&[(5000.0, 0.5)]);
+    let mut filters = FilterChain::new(vec![FilterSpec::LowPass(500.0)]);
+    checks.push((
+        "filter",
+        filters
+            .configure(1, SELF_TEST_RATE)
+            .map_err(|err| err.to_string())
+            .and_then(|()| {
+                filters.process(&mut signal);
+                // skip the filter settling in
+                let peak = signal[SELF_TEST_FRAMES / 2..]
+                    .iter()
+                    .fold(0.0f32, |peak, s| peak.max(s.abs()));
+                close("5000 Hz through a 500 Hz low-pass", peak, 0.0, 0.01)
+            }),
+    ));
+
+    let decoded = [
+        SampleFormat::F32.decode(&0.25f32.to_le_bytes()),
+        SampleFormat::S32.decode(&(i32::MIN / 4).to_le_bytes()),
+        SampleFormat::S16.decode(&(i16::MAX / 2 + 1).to_le_bytes()),
+    ];
+    checks.push((
+        "decode",
+        close("f32", decoded[0], 0.25, 0.0)
+            .and(close("s32", decoded[1], -0.25, 1e-6))
+            .and(close("s16", decoded[2], 0.5, 1e-4)),
+    ));
+
+    let mut passed = true;
+    for (name, result) in checks {
+        match result {
+            Ok(()) => println!("PASS {}", name),
+            Err(err) => {
+                println!("FAIL {}: {}", name, err);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
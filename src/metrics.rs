@@ -0,0 +1,190 @@
+//! A Prometheus exporter for the analysis results.
+
+use crate::{Level, Pitch};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// How long a scrape may take to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Counters and the latest readings, as exposed on `/metrics`.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    /// Buffers analyzed.
+    pub buffers: u64,
+    /// Frames analyzed.
+    pub frames: u64,
+    /// Analyzed buffers whose results were lost before reaching us.
+    pub dropped: u64,
+    /// Graph cycles that didn't hand us a buffer.
+    pub xruns: u64,
+    /// NaN or infinite input samples replaced with silence.
+    pub non_finite: u64,
+    /// Clipped samples per channel.
+    pub clipped: Vec<u64>,
+    /// Levels of the last buffer.
+    pub levels: Vec<Level>,
+    pub pitch: Option<Pitch>,
+    pub correlation: Option<f32>,
+}
+
+impl Metrics {
+    /// Account for one analyzed buffer.
+    pub fn record(
+        &mut self,
+        n_frames: usize,
+        levels: &[Level],
+        pitch: Option<Pitch>,
+        correlation: Option<f32>,
+    ) {
+        self.buffers += 1;
+        self.frames += n_frames as u64;
+        if self.clipped.len() < levels.len() {
+            self.clipped.resize(levels.len(), 0);
+        }
+        for (clipped, level) in self.clipped.iter_mut().zip(levels) {
+            *clipped += level.clipped as u64;
+        }
+        self.levels.clear();
+        self.levels.extend_from_slice(levels);
+        self.pitch = pitch;
+        self.correlation = correlation;
+    }
+
+    /// Format the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+            if samples.is_empty() {
+                return;
+            }
+            let _ = writeln!(out, "# HELP audio_monitor_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE audio_monitor_{} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "audio_monitor_{}{} {}", name, labels, value);
+            }
+        };
+        let single = |value: f64| vec![(String::new(), value)];
+        let per_channel = |values: &mut dyn Iterator<Item = f64>| -> Vec<(String, f64)> {
+            values
+                .enumerate()
+                .map(|(c, value)| (format!("{{channel=\"{}\"}}", c), value))
+                .collect()
+        };
+
+        metric(
+            "buffers_total",
+            "counter",
+            "Buffers analyzed.",
+            &single(self.buffers as f64),
+        );
+        metric(
+            "frames_total",
+            "counter",
+            "Frames analyzed.",
+            &single(self.frames as f64),
+        );
+        metric(
+            "dropped_total",
+            "counter",
+            "Analyzed buffers whose results were dropped by a full queue.",
+            &single(self.dropped as f64),
+        );
+        metric(
+            "xruns_total",
+            "counter",
+            "Graph cycles that found no buffer to dequeue.",
+            &single(self.xruns as f64),
+        );
+        metric(
+            "non_finite_samples_total",
+            "counter",
+            "NaN or infinite input samples replaced with silence.",
+            &single(self.non_finite as f64),
+        );
+        metric(
+            "clipped_samples_total",
+            "counter",
+            "Input samples at or above the clip threshold.",
+            &per_channel(&mut self.clipped.iter().map(|&n| n as f64)),
+        );
+        metric(
+            "peak",
+            "gauge",
+            "Absolute sample peak of the last buffer.",
+            &per_channel(&mut self.levels.iter().map(|l| l.peak as f64)),
+        );
+        metric(
+            "rms",
+            "gauge",
+            "RMS level of the last buffer.",
+            &per_channel(&mut self.levels.iter().map(|l| l.rms as f64)),
+        );
+        if let Some(pitch) = self.pitch {
+            metric(
+                "pitch_hz",
+                "gauge",
+                "Fundamental frequency of the last voiced buffer.",
+                &single(pitch.freq as f64),
+            );
+        }
+        if let Some(correlation) = self.correlation {
+            metric(
+                "correlation",
+                "gauge",
+                "Correlation between channels 0 and 1 of the last buffer.",
+                &single(correlation as f64),
+            );
+        }
+        out
+    }
+}
+
+/// Serve `metrics` over HTTP at `/metrics` from a background thread,
+/// one connection at a time.
+pub fn serve_metrics(
+    addr: impl ToSocketAddrs,
+    metrics: Arc<Mutex<Metrics>>,
+) -> io::Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    thread::Builder::new()
+        .name("metrics".into())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // a misbehaving client only costs its own connection
+                let _ = respond(stream, &metrics);
+            }
+        })
+}
+
+fn respond(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers, we don't need any of them
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.lock().map(|m| m.render()).unwrap_or_default();
+            ("200 OK", body)
+        }
+        _ => ("404 Not Found", String::from("not found\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
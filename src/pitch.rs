@@ -0,0 +1,246 @@
+//! Fundamental frequency estimation for monophonic sources.
+
+/// Estimated fundamental of a buffer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Pitch {
+    /// Fundamental frequency in Hz.
+    pub freq: f32,
+    /// How periodic the buffer is at `freq`, from 0 to 1.
+    pub confidence: f32,
+}
+
+/// Buffers quieter than this RMS are treated as silence rather than
+/// searched for a period.
+const SILENCE_RMS: f32 = 1e-4;
+
+/// Most frames searched for a period. The difference function costs
+/// `n^2 / 4` multiply-adds for `n` frames, so longer buffers only have
+/// their last `MAX_FRAMES` searched to keep the data thread in budget.
+/// That still finds fundamentals down to 47 Hz at 48 kHz.
+const MAX_FRAMES: usize = 2048;
+
+/// A YIN pitch detector.
+///
+/// The channels of each buffer are mixed to mono and searched for periods
+/// up to half the buffer length, so the lowest detectable frequency is
+/// `2 * rate / n_frames`. Only the last 2048 frames of longer buffers are
+/// searched. Scratch space is kept between calls and only grows when a
+/// longer buffer arrives.
+#[derive(Clone, Debug)]
+pub struct PitchDetector {
+    /// Highest normalized difference still accepted as a period; YIN's
+    /// absolute threshold.
+    threshold: f32,
+    mono: Vec<f32>,
+    diff: Vec<f32>,
+}
+
+impl Default for PitchDetector {
+    fn default() -> Self {
+        PitchDetector::new(0.15)
+    }
+}
+
+impl PitchDetector {
+    pub fn new(threshold: f32) -> Self {
+        PitchDetector {
+            threshold,
+            mono: Vec::new(),
+            diff: Vec::new(),
+        }
+    }
+
+    /// Allocate scratch space for buffers of up to `max_frames`.
+    pub fn reserve(&mut self, max_frames: usize) {
+        let max_frames = max_frames.min(MAX_FRAMES);
+        self.mono
+            .reserve(max_frames.saturating_sub(self.mono.len()));
+        self.diff
+            .reserve((max_frames / 2).saturating_sub(self.diff.len()));
+    }
+
+    /// Estimate the fundamental of one buffer of interleaved samples.
+    /// Returns `None` for silence and for buffers without a clear period,
+    /// such as noise or chords.
+    pub fn detect(&mut self, samples: &[f32], n_channels: usize, rate: u32) -> Option<Pitch> {
+        if n_channels == 0 || rate == 0 {
+            return None;
+        }
+
+        let n_frames = samples.len() / n_channels;
+        let skip = n_frames.saturating_sub(MAX_FRAMES);
+        self.mono.clear();
+        self.mono.extend(
+            samples[skip * n_channels..]
+                .chunks_exact(n_channels)
+                .map(|frame| frame.iter().sum::<f32>() / n_channels as f32),
+        );
+        let x = &self.mono;
+        let rms = (x.iter().map(|s| s * s).sum::<f32>() / x.len().max(1) as f32).sqrt();
+        if rms < SILENCE_RMS {
+            return None;
+        }
+
+        // difference function over a window of half the buffer
+        let window = x.len() / 2;
+        if window < 2 {
+            return None;
+        }
+        self.diff.clear();
+        self.diff.resize(window, 0.0);
+        for (tau, diff) in self.diff.iter_mut().enumerate().skip(1) {
+            *diff = x[..window]
+                .iter()
+                .zip(&x[tau..tau + window])
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum();
+        }
+
+        // cumulative mean normalized difference
+        let d = &mut self.diff;
+        d[0] = 1.0;
+        let mut running_sum = 0.0;
+        for (tau, diff) in d.iter_mut().enumerate().skip(1) {
+            running_sum += *diff;
+            *diff = if running_sum > 0.0 {
+                *diff * tau as f32 / running_sum
+            } else {
+                1.0
+            };
+        }
+
+        // first dip under the threshold, followed down to its minimum
+        let mut tau = (2..window).find(|&tau| d[tau] < self.threshold)?;
+        while tau + 1 < window && d[tau + 1] < d[tau] {
+            tau += 1;
+        }
+
+        // refine between samples with a parabola through the neighbours
+        let period = if tau + 1 < window {
+            let (a, b, c) = (d[tau - 1], d[tau], d[tau + 1]);
+            let denom = a - 2.0 * b + c;
+            if denom.abs() > f32::EPSILON {
+                tau as f32 + 0.5 * (a - c) / denom
+            } else {
+                tau as f32
+            }
+        } else {
+            tau as f32
+        };
+
+        Some(Pitch {
+            freq: rate as f32 / period,
+            confidence: (1.0 - d[tau]).clamp(0.0, 1.0),
+        })
+    }
+}
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+/// The equal-tempered note nearest to a frequency.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Note {
+    /// Pitch class, e.g. `"C#"`.
+    pub name: &'static str,
+    /// Scientific pitch notation octave, so middle C is C4.
+    pub octave: i32,
+    /// How far the frequency is above the note, from -50 to +50.
+    pub cents: f32,
+}
+
+impl Note {
+    /// The note nearest `freq` Hz when A4 is tuned to `a4` Hz. `None` for
+    /// frequencies that aren't positive.
+    pub fn nearest(freq: f32, a4: f32) -> Option<Note> {
+        if !(freq > 0.0 && a4 > 0.0) {
+            return None;
+        }
+        // semitones above C0, where A4 is 57
+        let semitones = 12.0 * (freq / a4).log2() + 57.0;
+        let nearest = semitones.round();
+        let index = nearest as i32;
+        Some(Note {
+            name: NOTE_NAMES[index.rem_euclid(12) as usize],
+            octave: index.div_euclid(12),
+            cents: (semitones - nearest) * 100.0,
+        })
+    }
+}
+
+/// Formats as `"A4 +3 cents"`.
+impl std::fmt::Display for Note {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{} {:+.0} cents", self.name, self.octave, self.cents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    fn sine(freq: f32, n_frames: usize) -> Vec<f32> {
+        (0..n_frames)
+            .map(|i| 0.5 * (TAU * freq * i as f32 / 48000.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn detects_a_sine() {
+        let pitch = PitchDetector::default().detect(&sine(440.0, 2048), 1, 48000);
+        let pitch = pitch.unwrap();
+        assert!((pitch.freq - 440.0).abs() < 1.0, "{}", pitch.freq);
+        assert!(pitch.confidence > 0.9);
+    }
+
+    #[test]
+    fn searches_only_the_end_of_long_buffers() {
+        // a long buffer that changes note at the end reads the last note,
+        // the same as the end on its own
+        let mut samples = sine(220.0, 16384);
+        samples.extend(sine(440.0, MAX_FRAMES));
+        let mut detector = PitchDetector::default();
+        let long = detector.detect(&samples, 1, 48000);
+        let end = detector.detect(&samples[samples.len() - MAX_FRAMES..], 1, 48000);
+        assert_eq!(long, end);
+        assert!(long.is_some_and(|pitch| (pitch.freq - 440.0).abs() < 1.0));
+    }
+
+    #[test]
+    fn names_concert_a() {
+        let note = Note::nearest(440.0, 440.0).unwrap();
+        assert_eq!((note.name, note.octave), ("A", 4));
+        assert!(note.cents.abs() < 0.01);
+        assert_eq!(note.to_string(), "A4 +0 cents");
+    }
+
+    #[test]
+    fn names_middle_c() {
+        let note = Note::nearest(261.63, 440.0).unwrap();
+        assert_eq!((note.name, note.octave), ("C", 4));
+        // B3 is just below, so the octave changes between them
+        let note = Note::nearest(246.94, 440.0).unwrap();
+        assert_eq!((note.name, note.octave), ("B", 3));
+    }
+
+    #[test]
+    fn measures_cents() {
+        // a quarter tone above A4
+        let note = Note::nearest(440.0 * 2f32.powf(0.25 / 12.0), 440.0).unwrap();
+        assert_eq!(note.name, "A");
+        assert!((note.cents - 25.0).abs() < 0.01, "{}", note.cents);
+        // a different tuning moves the notes with it
+        let note = Note::nearest(432.0, 432.0).unwrap();
+        assert_eq!((note.name, note.octave), ("A", 4));
+    }
+
+    #[test]
+    fn rejects_non_positive_frequencies() {
+        assert_eq!(Note::nearest(0.0, 440.0), None);
+        assert_eq!(Note::nearest(-440.0, 440.0), None);
+        assert_eq!(Note::nearest(f32::NAN, 440.0), None);
+        assert_eq!(Note::nearest(440.0, 0.0), None);
+    }
+}
@@ -0,0 +1,111 @@
+//! Reading samples back from a WAV file.
+
+use crate::source::AudioSource;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::time::Duration;
+
+/// Interleaved `f32` frames decoded from a WAV file.
+pub struct WavSource {
+    n_channels: usize,
+    rate: u32,
+    samples: Samples,
+}
+
+enum Samples {
+    Float(hound::WavIntoSamples<BufReader<File>, f32>),
+    /// Integer samples and the scale that normalizes them to [-1, 1].
+    Int(hound::WavIntoSamples<BufReader<File>, i32>, f32),
+}
+
+impl WavSource {
+    pub fn open(path: &Path) -> hound::Result<Self> {
+        let reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let samples = match spec.sample_format {
+            hound::SampleFormat::Float => Samples::Float(reader.into_samples()),
+            hound::SampleFormat::Int => Samples::Int(
+                reader.into_samples(),
+                (1u64 << (spec.bits_per_sample - 1)) as f32,
+            ),
+        };
+        Ok(WavSource {
+            n_channels: spec.channels as usize,
+            rate: spec.sample_rate,
+            samples,
+        })
+    }
+}
+
+impl AudioSource for WavSource {
+    type Error = hound::Error;
+
+    fn n_channels(&self) -> usize {
+        self.n_channels
+    }
+
+    fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Never waits, a file has every frame ready. Returns `None` at the end
+    /// of the file; a trailing partial frame is dropped.
+    fn read(&mut self, samples: &mut [f32], _timeout: Duration) -> hound::Result<Option<usize>> {
+        let n_channels = self.n_channels.max(1);
+        let len = samples.len() / n_channels * n_channels;
+        let mut n = 0;
+        for slot in &mut samples[..len] {
+            let sample = match &mut self.samples {
+                Samples::Float(samples) => samples.next().transpose()?,
+                Samples::Int(samples, scale) => samples
+                    .next()
+                    .transpose()?
+                    .map(|sample| sample as f32 / *scale),
+            };
+            let Some(sample) = sample else {
+                break;
+            };
+            *slot = sample;
+            n += 1;
+        }
+        // nothing left, or only part of a frame
+        if n < n_channels && len > 0 {
+            return Ok(None);
+        }
+        Ok(Some(n / n_channels))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_integer_samples_normalized() {
+        let path = std::env::temp_dir().join(format!("wav-source-{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..2000 {
+            let sample = if i % 2 == 0 { 16384i16 } else { i16::MIN };
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let mut source = WavSource::open(&path).unwrap();
+        assert_eq!((source.n_channels(), source.rate()), (2, 44100));
+        let mut samples = vec![0.0; 1024];
+        let mut frames = 0;
+        while let Some(n_frames) = source.read(&mut samples, Duration::ZERO).unwrap() {
+            assert_eq!(&samples[..2], [0.5, -1.0]);
+            frames += n_frames;
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(frames, 1000);
+    }
+}
@@ -0,0 +1,1007 @@
+//! The per-buffer analysis pipeline.
+
+use crate::envelope::{Ballistics, EnvelopeDetector, EnvelopeFollower};
+use crate::filter::DcBlocker;
+use crate::goertzel::goertzel;
+use crate::loudness::{Loudness, LoudnessMeter};
+use crate::pitch::{Pitch, PitchDetector};
+use crate::true_peak::TruePeakMeter;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Level readings for one channel of a buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Level {
+    /// Absolute sample peak.
+    pub peak: f32,
+    /// Estimated peak between samples, at least `peak`. `None` without
+    /// [`ProcessorConfig::true_peak`].
+    pub true_peak: Option<f32>,
+    /// Root mean square, `sqrt(mean(sample^2))`.
+    pub rms: f32,
+    /// Input samples at or above the clip threshold, counted by
+    /// [`AudioProcessor::count_clipped`] before any filter or gain.
+    pub clipped: usize,
+    /// The highest recent peak, falling off at
+    /// [`ProcessorConfig::peak_hold_decay_db`]. `None` without peak hold.
+    pub peak_hold: Option<f32>,
+    /// The level with [`ProcessorConfig::ballistics`] applied. `None` for
+    /// digital meters, which show `peak`.
+    pub meter: Option<f32>,
+}
+
+impl Level {
+    /// Ratio of the peak to the RMS level, high for impulses and low for
+    /// steady tones. `None` for a silent buffer.
+    pub fn crest_factor(&self) -> Option<f32> {
+        (self.rms > 0.0).then(|| self.peak / self.rms)
+    }
+}
+
+/// Per-channel gains, either as trims in dB for given channels as by
+/// `--channel-gains`, or as one linear gain per channel in order as by
+/// `--gain`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChannelGains {
+    trims: Vec<(usize, f32)>,
+    /// Linear gains from channel 0 up; the last one repeats for the rest.
+    list: Vec<f32>,
+}
+
+impl ChannelGains {
+    /// Parse `"1.0,2.0,0.5"`, one gain per channel starting at channel 0.
+    /// Gains are linear unless they end in `dB`, e.g. `"0dB,+6dB"`.
+    pub fn parse_list(s: &str) -> Result<Self, String> {
+        let mut list = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let gain = match entry
+                .strip_suffix("dB")
+                .or_else(|| entry.strip_suffix("db"))
+            {
+                Some(db) => {
+                    let db: f32 = db
+                        .trim()
+                        .parse()
+                        .map_err(|_| format!("invalid gain \"{}\"", entry))?;
+                    10f32.powf(db / 20.0)
+                }
+                None => entry
+                    .parse()
+                    .map_err(|_| format!("invalid gain \"{}\"", entry))?,
+            };
+            list.push(gain);
+        }
+        Ok(ChannelGains {
+            trims: Vec::new(),
+            list,
+        })
+    }
+
+    /// Expand the gains into one linear gain per channel. Channels after
+    /// the end of a list repeat its last gain; channels without an explicit
+    /// trim are left at unity gain.
+    pub fn linear(&self, n_channels: usize) -> Result<Vec<f32>, ConfigError> {
+        if self.list.len() > n_channels {
+            return Err(ConfigError::TooManyGains {
+                given: self.list.len(),
+                n_channels,
+            });
+        }
+        let mut gains = vec![self.list.last().copied().unwrap_or(1.0); n_channels];
+        gains[..self.list.len()].copy_from_slice(&self.list);
+        for &(channel, db) in &self.trims {
+            let Some(gain) = gains.get_mut(channel) else {
+                return Err(ConfigError::GainChannelOutOfRange {
+                    channel,
+                    n_channels,
+                });
+            };
+            *gain = 10f32.powf(db / 20.0);
+        }
+        Ok(gains)
+    }
+}
+
+/// Parses `"0:0dB,1:+3dB,2:-2dB"`. The `dB` suffix is optional.
+impl FromStr for ChannelGains {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut trims = Vec::new();
+        for entry in s.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (channel, db) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("expected <channel>:<gain>dB, got \"{}\"", entry))?;
+            let channel: usize = channel
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid channel index \"{}\"", channel))?;
+            let db = db.trim();
+            let db = db
+                .strip_suffix("dB")
+                .or_else(|| db.strip_suffix("db"))
+                .unwrap_or(db);
+            let db: f32 = db
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid gain \"{}\" for channel {}", db, channel))?;
+            trims.push((channel, db));
+        }
+        Ok(ChannelGains {
+            trims,
+            list: Vec::new(),
+        })
+    }
+}
+
+/// How channels are mixed to mono for tone and pitch analysis, as given
+/// by `--downmix` or `--channel`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Downmix {
+    /// The mean of all channels. A signal present on every channel keeps
+    /// its level, anything on one channel only drops by `1/n`.
+    #[default]
+    Average,
+    /// The sum of all channels scaled by `1/sqrt(n)`, i.e. 3 dB down per
+    /// doubling of channels. Uncorrelated channels keep their combined
+    /// power; identical channels read `sqrt(n)` times louder.
+    Sum,
+    /// Channel 0 only.
+    First,
+    /// One channel only, which the stream has to have.
+    Channel(usize),
+}
+
+impl Downmix {
+    fn mix(self, frame: &[f32]) -> f32 {
+        match self {
+            Downmix::Average => frame.iter().sum::<f32>() / frame.len() as f32,
+            Downmix::Sum => frame.iter().sum::<f32>() / (frame.len() as f32).sqrt(),
+            Downmix::First => frame[0],
+            // only reached with a channel that didn't pass `configure`
+            Downmix::Channel(c) => frame.get(c).copied().unwrap_or(0.0),
+        }
+    }
+}
+
+impl FromStr for Downmix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "average" => Ok(Downmix::Average),
+            "sum" => Ok(Downmix::Sum),
+            "first" => Ok(Downmix::First),
+            _ => Err(format!(
+                "unknown downmix \"{}\", expected sum, average or first",
+                s
+            )),
+        }
+    }
+}
+
+/// Why a [`ProcessorConfig`] can't be applied to the negotiated stream.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    /// A gain trim was given for a channel the stream doesn't have.
+    GainChannelOutOfRange { channel: usize, n_channels: usize },
+    /// More `--gain` values were given than the stream has channels.
+    TooManyGains { given: usize, n_channels: usize },
+    /// A `--filter` corner frequency at or above the Nyquist frequency.
+    FilterAboveNyquist { freq: f32, rate: u32 },
+    /// `--channel` selected a channel the stream doesn't have.
+    ChannelOutOfRange { channel: usize, n_channels: usize },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::GainChannelOutOfRange {
+                channel,
+                n_channels,
+            } => write!(
+                f,
+                "gain given for channel {} but the stream only has {} channels",
+                channel, n_channels
+            ),
+            ConfigError::TooManyGains { given, n_channels } => write!(
+                f,
+                "{} gains given but the stream only has {} channels",
+                given, n_channels
+            ),
+            ConfigError::FilterAboveNyquist { freq, rate } => write!(
+                f,
+                "filter at {} Hz is above the Nyquist frequency of {} Hz audio",
+                freq, rate
+            ),
+            ConfigError::ChannelOutOfRange {
+                channel,
+                n_channels,
+            } => write!(
+                f,
+                "channel {} selected but the stream only has {} channels",
+                channel, n_channels
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Settings for an [`AudioProcessor`].
+#[derive(Clone, Debug)]
+pub struct ProcessorConfig {
+    /// Gain of each channel, applied to the buffer before anything is
+    /// measured from it.
+    pub channel_gains: ChannelGains,
+    /// Estimate the fundamental of each buffer, see [`AudioProcessor::pitch`].
+    pub pitch: bool,
+    /// Measure loudness, see [`AudioProcessor::loudness`].
+    pub loudness: bool,
+    /// Oversample each buffer to estimate [`Level::true_peak`].
+    pub true_peak: bool,
+    /// Run each channel through a [`DcBlocker`] before measuring it, so a
+    /// DC bias from the interface doesn't read as level.
+    pub remove_dc: bool,
+    /// Frequencies in Hz to measure on every buffer, see
+    /// [`AudioProcessor::tone_magnitudes`].
+    pub tones: Vec<f32>,
+    /// How to mix to mono for the tones and pitch.
+    pub downmix: Downmix,
+    /// Absolute sample value counted as clipping, see [`Level::clipped`].
+    pub clip_threshold: f32,
+    /// RMS level in dBFS below which every channel has to stay for the
+    /// input to count as silent, see [`AudioProcessor::is_silent`].
+    pub silence_threshold_db: Option<f32>,
+    /// How long the input has to stay below `silence_threshold_db` before
+    /// it counts as silent, so it doesn't flap at the threshold.
+    pub silence_hold: Duration,
+    /// Hold each channel's peak and let it fall by this many dB per second,
+    /// see [`Level::peak_hold`].
+    pub peak_hold_decay_db: Option<f32>,
+    /// Follow the loudest channel's level over time, see
+    /// [`AudioProcessor::envelope`].
+    pub envelope: Option<EnvelopeDetector>,
+    /// How fast the envelope rises.
+    pub envelope_attack: Duration,
+    /// How fast the envelope falls.
+    pub envelope_release: Duration,
+    /// Estimate how far channel 1 lags channel 0, searching up to this far
+    /// either way, see [`AudioProcessor::delay`].
+    pub max_delay: Option<Duration>,
+    /// How the per-channel [`Level::meter`] responds.
+    pub ballistics: Ballistics,
+    /// Crest factor in dB above which any channel marks the buffer as a
+    /// transient, see [`AudioProcessor::is_transient`].
+    pub crest_threshold_db: Option<f32>,
+}
+
+impl Default for ProcessorConfig {
+    fn default() -> Self {
+        ProcessorConfig {
+            channel_gains: ChannelGains::default(),
+            pitch: false,
+            loudness: false,
+            true_peak: false,
+            remove_dc: true,
+            tones: Vec::new(),
+            downmix: Downmix::Average,
+            clip_threshold: 0.999,
+            silence_threshold_db: None,
+            silence_hold: Duration::from_millis(500),
+            peak_hold_decay_db: None,
+            envelope: None,
+            envelope_attack: Duration::from_millis(10),
+            envelope_release: Duration::from_millis(300),
+            max_delay: None,
+            ballistics: Ballistics::Digital,
+            crest_threshold_db: None,
+        }
+    }
+}
+
+/// Runs the analysis on one buffer at a time.
+///
+/// Everything sized from the stream format is kept between calls, so once
+/// the first buffer of a format has been seen, or after
+/// [`configure`](Self::configure) and [`reserve`](Self::reserve),
+/// `process_frame` doesn't allocate and is safe to call from a real-time
+/// thread.
+pub struct AudioProcessor {
+    config: ProcessorConfig,
+    n_channels: usize,
+    rate: u32,
+    /// Linear gain per channel, expanded from `config.channel_gains`.
+    gains: Vec<f32>,
+    levels: Vec<Level>,
+    /// Input samples per channel counted by `count_clipped`, for the next
+    /// buffer's levels.
+    clipped: Vec<usize>,
+    correlation: Option<f32>,
+    delay: Option<f32>,
+    /// `Some` when `config.pitch` is set.
+    pitch_detector: Option<PitchDetector>,
+    pitch: Option<Pitch>,
+    /// `Some` when `config.loudness` is set, for the current format.
+    loudness_meter: Option<LoudnessMeter>,
+    /// `Some` when `config.true_peak` is set, for the current format.
+    true_peak_meter: Option<TruePeakMeter>,
+    /// `Some` when `config.remove_dc` is set, for the current format.
+    dc_blocker: Option<DcBlocker>,
+    /// Magnitude at each of `config.tones`.
+    tone_magnitudes: Vec<f32>,
+    /// The buffer mixed to mono after the gains, for the tones and pitch.
+    mono: Vec<f32>,
+    /// `Some` when `config.envelope` is set.
+    envelope: Option<EnvelopeFollower>,
+    /// One per channel, empty for digital ballistics.
+    meters: Vec<EnvelopeFollower>,
+    /// Frames in a row below the silence threshold.
+    quiet_frames: u64,
+    silent: bool,
+    transient: bool,
+}
+
+impl AudioProcessor {
+    pub fn new(config: ProcessorConfig) -> Self {
+        AudioProcessor {
+            n_channels: 0,
+            rate: 0,
+            gains: Vec::new(),
+            levels: Vec::new(),
+            clipped: Vec::new(),
+            correlation: None,
+            delay: None,
+            pitch_detector: config.pitch.then(PitchDetector::default),
+            pitch: None,
+            loudness_meter: None,
+            true_peak_meter: None,
+            dc_blocker: None,
+            tone_magnitudes: vec![0.0; config.tones.len()],
+            mono: Vec::new(),
+            envelope: config.envelope.map(|_| {
+                EnvelopeFollower::new(
+                    config.envelope_attack.as_secs_f32(),
+                    config.envelope_release.as_secs_f32(),
+                )
+            }),
+            meters: Vec::new(),
+            quiet_frames: 0,
+            silent: false,
+            transient: false,
+            config,
+        }
+    }
+
+    /// Prepare for a stream of `n_channels` at `rate`, checking the
+    /// configuration against it. Call this whenever the format changes.
+    pub fn configure(&mut self, n_channels: usize, rate: u32) -> Result<(), ConfigError> {
+        let gains = self.config.channel_gains.linear(n_channels)?;
+        if let Downmix::Channel(channel) = self.config.downmix
+            && channel >= n_channels
+        {
+            return Err(ConfigError::ChannelOutOfRange {
+                channel,
+                n_channels,
+            });
+        }
+        self.reset(n_channels, rate, gains);
+        Ok(())
+    }
+
+    fn reset(&mut self, n_channels: usize, rate: u32, gains: Vec<f32>) {
+        self.n_channels = n_channels;
+        self.rate = rate;
+        self.gains = gains;
+        self.levels.clear();
+        self.levels.resize(n_channels, Level::default());
+        self.clipped.clear();
+        self.clipped.resize(n_channels, 0);
+        self.correlation = None;
+        self.delay = None;
+        self.pitch = None;
+        self.loudness_meter = self
+            .config
+            .loudness
+            .then(|| LoudnessMeter::new(n_channels, rate));
+        self.true_peak_meter = self
+            .config
+            .true_peak
+            .then(|| TruePeakMeter::new(n_channels));
+        self.dc_blocker = self
+            .config
+            .remove_dc
+            .then(|| DcBlocker::new(n_channels, rate));
+        if let Some(envelope) = &mut self.envelope {
+            envelope.reset();
+        }
+        self.meters.clear();
+        if let Some(meter) = self.config.ballistics.follower() {
+            self.meters.resize(n_channels, meter);
+        }
+        self.quiet_frames = 0;
+        self.silent = false;
+        self.transient = false;
+    }
+
+    /// Allocate scratch space for buffers of up to `max_frames` up front,
+    /// so that `process_frame` doesn't allocate on the first buffer.
+    pub fn reserve(&mut self, max_frames: usize) {
+        self.mono
+            .reserve(max_frames.saturating_sub(self.mono.len()));
+        if let Some(detector) = &mut self.pitch_detector {
+            detector.reserve(max_frames);
+        }
+    }
+
+    /// Level of each channel in the last buffer, as returned by
+    /// [`process_frame`](Self::process_frame).
+    pub fn levels(&self) -> &[Level] {
+        &self.levels
+    }
+
+    /// Sample rate the processor is configured for.
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Correlation coefficient between channels 0 and 1 of the last buffer,
+    /// from -1 (out of phase) through 0 (unrelated) to +1 (mono
+    /// compatible). `None` for mono streams and when either channel is
+    /// silent.
+    pub fn correlation(&self) -> Option<f32> {
+        self.correlation
+    }
+
+    /// How many frames channel 1 lagged behind channel 0 in the last
+    /// buffer, negative when it led, from the peak of their
+    /// cross-correlation. `None` without [`ProcessorConfig::max_delay`],
+    /// for mono streams and when the channels don't correlate.
+    pub fn delay(&self) -> Option<f32> {
+        self.delay
+    }
+
+    /// Whether the input has been below the silence threshold for at least
+    /// the hold time. Always `false` without a threshold.
+    pub fn is_silent(&self) -> bool {
+        self.silent
+    }
+
+    /// Whether any channel's crest factor in the last buffer was above
+    /// [`ProcessorConfig::crest_threshold_db`]. Always `false` without a
+    /// threshold.
+    pub fn is_transient(&self) -> bool {
+        self.transient
+    }
+
+    /// Fundamental of the last buffer, if pitch detection is enabled and
+    /// the buffer was voiced.
+    pub fn pitch(&self) -> Option<Pitch> {
+        self.pitch
+    }
+
+    /// Loudness up to the last buffer, if loudness metering is enabled.
+    /// The meter starts over when the format changes.
+    pub fn loudness(&self) -> Option<Loudness> {
+        self.loudness_meter.as_ref().map(LoudnessMeter::loudness)
+    }
+
+    /// Smoothed level of the loudest channel up to the last buffer, linear,
+    /// if an envelope is configured.
+    pub fn envelope(&self) -> Option<f32> {
+        self.envelope.as_ref().and_then(EnvelopeFollower::value)
+    }
+
+    /// Magnitude of the last buffer, mixed to mono by
+    /// [`ProcessorConfig::downmix`], at each of the
+    /// configured tone frequencies, in the same order.
+    pub fn tone_magnitudes(&self) -> &[f32] {
+        &self.tone_magnitudes
+    }
+
+    /// Count the samples of each channel at or above the clip threshold in
+    /// interleaved input, before anything has filtered or amplified it,
+    /// towards [`Level::clipped`] of the next
+    /// [`process_frame`](Self::process_frame).
+    pub fn count_clipped(&mut self, samples: &[f32]) {
+        let n_channels = self.n_channels;
+        if n_channels == 0 {
+            return;
+        }
+        for frame in samples.chunks_exact(n_channels) {
+            for (clipped, sample) in self.clipped.iter_mut().zip(frame) {
+                if sample.abs() >= self.config.clip_threshold {
+                    *clipped += 1;
+                }
+            }
+        }
+    }
+
+    /// Analyze one buffer of interleaved samples and return the level of
+    /// each channel.
+    ///
+    /// The samples are conditioned in place before anything is measured:
+    /// the DC offset is removed and each channel is scaled by its gain. The
+    /// levels, tones, pitch, correlation and loudness are all taken from
+    /// the result, which is left in `samples` for the caller to record.
+    ///
+    /// If `n_channels` or `rate` differ from the current configuration the
+    /// processor reconfigures itself first; settings that don't fit the new
+    /// format (see [`configure`](Self::configure)) fall back to unity gain.
+    pub fn process_frame(&mut self, samples: &mut [f32], n_channels: usize, rate: u32) -> &[Level] {
+        if (n_channels != self.n_channels || rate != self.rate)
+            && self.configure(n_channels, rate).is_err()
+        {
+            self.reset(n_channels, rate, vec![1.0; n_channels]);
+        }
+        if n_channels == 0 {
+            return &self.levels;
+        }
+
+        let elapsed = (samples.len() / n_channels) as f32 / rate as f32;
+        for (c, level) in self.levels.iter_mut().enumerate() {
+            let gain = self.gains[c];
+            let mut peak: f32 = 0.0;
+            let mut sum_squares = 0.0;
+            let mut count = 0;
+            let mut true_peak: f32 = 0.0;
+            for sample in samples.iter_mut().skip(c).step_by(n_channels) {
+                let f = match &mut self.dc_blocker {
+                    Some(blocker) => blocker.process(c, *sample),
+                    None => *sample,
+                } * gain;
+                *sample = f;
+                peak = peak.max(f.abs());
+                if let Some(meter) = &mut self.true_peak_meter {
+                    true_peak = true_peak.max(meter.process(c, f));
+                }
+                sum_squares += f * f;
+                count += 1;
+            }
+            let peak_hold = self.config.peak_hold_decay_db.map(|decay| {
+                let held = level.peak_hold.unwrap_or(0.0) * 10f32.powf(-decay * elapsed / 20.0);
+                held.max(peak)
+            });
+            *level = Level {
+                peak,
+                true_peak: self
+                    .true_peak_meter
+                    .is_some()
+                    .then_some(true_peak.max(peak)),
+                rms: if count > 0 {
+                    (sum_squares / count as f32).sqrt()
+                } else {
+                    0.0
+                },
+                clipped: mem::take(&mut self.clipped[c]),
+                peak_hold,
+                meter: None,
+            };
+            if let Some(meter) = self.meters.get_mut(c) {
+                let value = match self.config.ballistics.detector() {
+                    EnvelopeDetector::Peak => level.peak,
+                    EnvelopeDetector::Rms => level.rms,
+                };
+                level.meter = Some(meter.process(value, elapsed));
+            }
+        }
+
+        if let (Some(envelope), Some(detector)) = (&mut self.envelope, self.config.envelope) {
+            let loudest = self
+                .levels
+                .iter()
+                .map(|level| match detector {
+                    EnvelopeDetector::Peak => level.peak,
+                    EnvelopeDetector::Rms => level.rms,
+                })
+                .fold(0.0, f32::max);
+            envelope.process(loudest, elapsed);
+        }
+
+        if let Some(threshold) = self.config.silence_threshold_db {
+            let loudest = self.levels.iter().map(|l| l.rms).fold(0.0, f32::max);
+            if 20.0 * loudest.log10() < threshold {
+                self.quiet_frames += (samples.len() / n_channels) as u64;
+                let hold_frames = self.config.silence_hold.as_secs_f64() * rate as f64;
+                self.silent = self.quiet_frames as f64 >= hold_frames;
+            } else {
+                self.quiet_frames = 0;
+                self.silent = false;
+            }
+        }
+
+        if let Some(threshold) = self.config.crest_threshold_db {
+            self.transient = self
+                .levels
+                .iter()
+                .filter_map(Level::crest_factor)
+                .any(|crest| 20.0 * crest.log10() > threshold);
+        }
+
+        let samples = &*samples;
+        self.correlation = if n_channels >= 2 {
+            correlation(samples, n_channels)
+        } else {
+            None
+        };
+        self.delay = match self.config.max_delay {
+            Some(max_delay) if n_channels >= 2 => {
+                let max_lag = (max_delay.as_secs_f64() * rate as f64).round() as usize;
+                delay(samples, n_channels, max_lag)
+            }
+            _ => None,
+        };
+
+        if !self.config.tones.is_empty() || self.pitch_detector.is_some() {
+            let downmix = self.config.downmix;
+            self.mono.clear();
+            self.mono.extend(
+                samples
+                    .chunks_exact(n_channels)
+                    .map(|frame| downmix.mix(frame)),
+            );
+        }
+
+        for (magnitude, &freq) in self.tone_magnitudes.iter_mut().zip(&self.config.tones) {
+            *magnitude = goertzel(self.mono.iter().copied(), freq, rate);
+        }
+
+        if let Some(detector) = &mut self.pitch_detector {
+            self.pitch = detector.detect(&self.mono, 1, rate);
+        }
+
+        if let Some(meter) = &mut self.loudness_meter {
+            meter.process(samples);
+        }
+
+        &self.levels
+    }
+}
+
+/// `sum(L*R) / sqrt(sum(L^2) * sum(R^2))` over channels 0 and 1.
+fn correlation(samples: &[f32], n_channels: usize) -> Option<f32> {
+    let (mut lr, mut ll, mut rr) = (0.0f32, 0.0f32, 0.0f32);
+    for frame in samples.chunks_exact(n_channels) {
+        let (l, r) = (frame[0], frame[1]);
+        lr += l * r;
+        ll += l * l;
+        rr += r * r;
+    }
+    let norm = (ll * rr).sqrt();
+    (norm > 0.0).then(|| (lr / norm).clamp(-1.0, 1.0))
+}
+
+/// Lag of channel 1 behind channel 0 in frames, at most `max_lag` either
+/// way, at the peak of their cross-correlation.
+/// The peak is refined between frames with a parabola through its
+/// neighbours. `None` if no lag correlates positively, e.g. for silence.
+fn delay(samples: &[f32], n_channels: usize, max_lag: usize) -> Option<f32> {
+    let n_frames = samples.len() / n_channels;
+    let max_lag = max_lag.min(n_frames.saturating_sub(1)) as isize;
+    let sample = |c: usize, i: usize| samples[i * n_channels + c];
+    // sum of ch0[i] * ch1[i + lag] where both exist, so there's no wrap
+    // around to untangle
+    let xcorr = |lag: isize| -> f32 {
+        let start = (-lag).max(0) as usize;
+        let end = (n_frames as isize - lag.max(0)) as usize;
+        (start..end)
+            .map(|i| sample(0, i) * sample(1, (i as isize + lag) as usize))
+            .sum()
+    };
+    let (lag, peak) = (-max_lag..=max_lag)
+        .map(|lag| (lag, xcorr(lag)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    if peak.is_nan() || peak <= 0.0 {
+        return None;
+    }
+    if lag.abs() == max_lag {
+        return Some(lag as f32);
+    }
+    let (a, c) = (xcorr(lag - 1), xcorr(lag + 1));
+    let denom = a - 2.0 * peak + c;
+    Some(if denom.abs() > f32::EPSILON {
+        lag as f32 + 0.5 * (a - c) / denom
+    } else {
+        lag as f32
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generator::{Signal, SignalGenerator};
+
+    const RATE: u32 = 48000;
+
+    /// `n_frames` of a sine at `freq` on each of `n_channels`.
+    fn sine(freq: f64, amplitude: f32, n_frames: usize, n_channels: usize) -> Vec<f32> {
+        let mut samples = vec![0.0; n_frames * n_channels];
+        SignalGenerator::new(Signal::Sine(freq), RATE, amplitude).fill(&mut samples, n_channels);
+        samples
+    }
+
+    fn assert_close(got: f32, want: f32, tolerance: f32) {
+        assert!(
+            (got - want).abs() <= tolerance,
+            "{} is not within {} of {}",
+            got,
+            tolerance,
+            want
+        );
+    }
+
+    #[test]
+    fn sine_levels() {
+        let mut processor = AudioProcessor::new(ProcessorConfig {
+            remove_dc: false,
+            ..Default::default()
+        });
+        let mut samples = sine(1000.0, 0.5, 4800, 2);
+        for level in processor.process_frame(&mut samples, 2, RATE) {
+            assert_close(level.peak, 0.5, 0.001);
+            assert_close(level.rms, 0.5 / 2f32.sqrt(), 0.001);
+            assert_eq!(level.clipped, 0);
+        }
+    }
+
+    #[test]
+    fn silence_has_no_level() {
+        let mut processor = AudioProcessor::new(ProcessorConfig::default());
+        let mut samples = vec![0.0; 960];
+        let level = processor.process_frame(&mut samples, 1, RATE)[0];
+        assert_eq!(level.peak, 0.0);
+        assert_eq!(level.rms, 0.0);
+        assert_eq!(level.crest_factor(), None);
+    }
+
+    #[test]
+    fn full_scale_samples_count_as_clipped() {
+        let mut processor = AudioProcessor::new(ProcessorConfig {
+            remove_dc: false,
+            ..Default::default()
+        });
+        let mut samples = vec![0.5, 1.0, -1.0, 0.0, 1.2, 0.99];
+        processor.configure(1, RATE).unwrap();
+        processor.count_clipped(&samples);
+        let level = processor.process_frame(&mut samples, 1, RATE)[0];
+        assert_eq!(level.clipped, 3);
+        // the count is for one buffer
+        let level = processor.process_frame(&mut samples, 1, RATE)[0];
+        assert_eq!(level.clipped, 0);
+        assert_close(level.peak, 1.2, 0.0);
+    }
+
+    #[test]
+    fn dc_removal_spans_buffers() {
+        // a 50 Hz sine is only a fraction of a cycle per 256-frame buffer,
+        // which subtracting each buffer's mean would bend out of shape
+        let mut processor = AudioProcessor::new(ProcessorConfig::default());
+        let mut output = Vec::new();
+        for chunk in sine(50.0, 0.5, 48000, 1).chunks(256) {
+            let mut samples: Vec<f32> = chunk.iter().map(|s| s + 0.25).collect();
+            processor.process_frame(&mut samples, 1, RATE);
+            output.extend(samples);
+        }
+        // the last 25 cycles have the offset removed and the sine intact
+        let settled = &output[24000..];
+        let mean = settled.iter().sum::<f32>() / settled.len() as f32;
+        let rms = (settled.iter().map(|s| s * s).sum::<f32>() / settled.len() as f32).sqrt();
+        assert_close(mean, 0.0, 0.005);
+        assert_close(rms, 0.5 / 2f32.sqrt(), 0.002);
+    }
+
+    #[test]
+    fn constant_offset_is_removed_at_once() {
+        let mut processor = AudioProcessor::new(ProcessorConfig::default());
+        let mut samples = vec![0.3; 256];
+        let level = processor.process_frame(&mut samples, 1, RATE)[0];
+        assert_close(level.peak, 0.0, 1e-6);
+    }
+
+    #[test]
+    fn channel_gains_parse() {
+        let gains: ChannelGains = "0:0dB, 1:+6dB,2:-6".parse().unwrap();
+        let linear = gains.linear(4).unwrap();
+        assert_close(linear[0], 1.0, 1e-6);
+        assert_close(linear[1], 1.9953, 1e-4);
+        assert_close(linear[2], 0.5012, 1e-4);
+        // channels without a trim stay at unity
+        assert_close(linear[3], 1.0, 1e-6);
+
+        assert!("1".parse::<ChannelGains>().is_err());
+        assert!("x:3dB".parse::<ChannelGains>().is_err());
+        assert!("1:loud".parse::<ChannelGains>().is_err());
+    }
+
+    #[test]
+    fn gain_list_parse() {
+        let gains = ChannelGains::parse_list("1.5, +6dB, -6db").unwrap();
+        let linear = gains.linear(3).unwrap();
+        assert_close(linear[0], 1.5, 1e-6);
+        assert_close(linear[1], 1.9953, 1e-4);
+        assert_close(linear[2], 0.5012, 1e-4);
+        assert!(ChannelGains::parse_list("1.0,half").is_err());
+        // no gains at all leaves every channel alone
+        assert_eq!(ChannelGains::default().linear(2).unwrap(), [1.0, 1.0]);
+    }
+
+    #[test]
+    fn too_many_gains_are_rejected() {
+        let gains = ChannelGains::parse_list("1.0,1.0,1.0").unwrap();
+        assert_eq!(
+            gains.linear(2),
+            Err(ConfigError::TooManyGains {
+                given: 3,
+                n_channels: 2
+            })
+        );
+    }
+
+    /// A processor with every analysis switched on.
+    fn everything() -> AudioProcessor {
+        AudioProcessor::new(ProcessorConfig {
+            pitch: true,
+            loudness: true,
+            true_peak: true,
+            tones: vec![440.0, 1000.0],
+            ballistics: Ballistics::Ppm,
+            peak_hold_decay_db: Some(20.0),
+            envelope: Some(EnvelopeDetector::Rms),
+            max_delay: Some(Duration::from_millis(1)),
+            crest_threshold_db: Some(12.0),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn survives_buffers_of_every_size() {
+        let mut processor = everything();
+        processor.configure(2, RATE).unwrap();
+        processor.reserve(256);
+        // empty, odd, a single frame, past the reservation and a ragged
+        // last frame
+        for n_samples in [0usize, 1, 2, 3, 255, 512, 16384, 1001] {
+            let mut samples = sine(1000.0, 0.5, n_samples.div_ceil(2), 2);
+            samples.truncate(n_samples);
+            let levels = processor.process_frame(&mut samples, 2, RATE);
+            assert_eq!(levels.len(), 2);
+            assert!(levels.iter().all(|level| level.peak <= 0.51));
+        }
+        let mut samples = sine(1000.0, 0.5, 4800, 2);
+        let levels = processor.process_frame(&mut samples, 2, RATE);
+        assert_close(levels[0].peak, 0.5, 0.01);
+        assert_close(levels[1].peak, 0.5, 0.01);
+    }
+
+    #[test]
+    fn follows_channel_count_and_rate_changes() {
+        let mut processor = everything();
+        for (n_channels, rate) in [(1, RATE), (6, RATE), (2, 44100), (1, 96000), (6, RATE)] {
+            for n_frames in [0, 7, 1024] {
+                let mut samples = sine(1000.0, 0.5, n_frames, n_channels);
+                let levels = processor.process_frame(&mut samples, n_channels, rate);
+                assert_eq!(levels.len(), n_channels);
+            }
+            assert_eq!(processor.tone_magnitudes().len(), 2);
+            let loudness = processor.loudness().unwrap();
+            assert!(loudness.momentary.is_none_or(f32::is_finite));
+        }
+        // no channels at all has nothing to report
+        assert!(processor.process_frame(&mut [], 0, RATE).is_empty());
+    }
+
+    #[test]
+    fn gains_apply_to_their_channels() {
+        let mut processor = AudioProcessor::new(ProcessorConfig {
+            channel_gains: "1:+6dB,2:-200dB".parse().unwrap(),
+            remove_dc: false,
+            ..Default::default()
+        });
+        let mut samples = sine(1000.0, 0.5, 4800, 3);
+        let levels = processor.process_frame(&mut samples, 3, RATE).to_vec();
+        assert_close(levels[0].peak, 0.5, 0.001);
+        assert_close(levels[1].peak, 0.5 * 10f32.powf(6.0 / 20.0), 0.002);
+        assert_close(levels[2].peak, 0.0, 1e-6);
+        // the buffer is left with the gains applied, for recording
+        let peak = |c: usize| {
+            samples
+                .iter()
+                .skip(c)
+                .step_by(3)
+                .fold(0.0f32, |peak, s| peak.max(s.abs()))
+        };
+        assert_close(peak(0), levels[0].peak, 0.0);
+        assert_close(peak(1), levels[1].peak, 0.0);
+        assert_close(peak(2), levels[2].peak, 0.0);
+    }
+
+    #[test]
+    fn gain_list_repeats_its_last_gain() {
+        let mut processor = AudioProcessor::new(ProcessorConfig {
+            channel_gains: ChannelGains::parse_list("2.0,0.5").unwrap(),
+            remove_dc: false,
+            ..Default::default()
+        });
+        let mut samples = sine(1000.0, 0.4, 4800, 4);
+        let levels = processor.process_frame(&mut samples, 4, RATE);
+        let peaks: Vec<f32> = levels.iter().map(|level| level.peak).collect();
+        for (got, want) in peaks.into_iter().zip([0.8, 0.2, 0.2, 0.2]) {
+            assert_close(got, want, 0.001);
+        }
+    }
+
+    #[test]
+    fn gains_apply_before_tones_and_pitch() {
+        // a loud 1000 Hz tone on channel 1, muted by its gain, and 440 Hz on
+        // channel 0
+        let (low, high) = (sine(440.0, 0.5, 4800, 1), sine(1000.0, 0.9, 4800, 1));
+        let stereo: Vec<f32> = low.iter().zip(&high).flat_map(|(&l, &h)| [l, h]).collect();
+        let analyze = |gains: &str| {
+            let mut processor = AudioProcessor::new(ProcessorConfig {
+                channel_gains: gains.parse().unwrap(),
+                tones: vec![440.0, 1000.0],
+                pitch: true,
+                ..Default::default()
+            });
+            let mut samples = stereo.clone();
+            processor.process_frame(&mut samples, 2, RATE);
+            (processor.tone_magnitudes().to_vec(), processor.pitch())
+        };
+
+        let (magnitudes, _) = analyze("");
+        assert_close(magnitudes[0], 0.25, 0.01);
+        assert_close(magnitudes[1], 0.45, 0.01);
+
+        let (magnitudes, pitch) = analyze("1:-200dB");
+        assert_close(magnitudes[0], 0.25, 0.01);
+        assert_close(magnitudes[1], 0.0, 0.001);
+        assert!(pitch.is_some_and(|pitch| (pitch.freq - 440.0).abs() < 2.0));
+    }
+
+    #[test]
+    fn gains_apply_before_correlation_and_loudness() {
+        let processor = |gains: &str| {
+            AudioProcessor::new(ProcessorConfig {
+                channel_gains: ChannelGains::parse_list(gains).unwrap(),
+                loudness: true,
+                ..Default::default()
+            })
+        };
+        let mut processors = [processor("1.0"), processor("1.0,0.0"), processor("0.0")];
+        for _ in 0..10 {
+            for processor in &mut processors {
+                let mut samples = sine(1000.0, 0.5, 4800, 2);
+                processor.process_frame(&mut samples, 2, RATE);
+            }
+        }
+        let [both, one, none] = processors;
+        assert_close(both.correlation().unwrap(), 1.0, 1e-4);
+        // a muted channel doesn't correlate with anything
+        assert_eq!(one.correlation(), None);
+        let momentary = |processor: &AudioProcessor| processor.loudness().unwrap().momentary;
+        // half the power, so 3 dB down
+        assert_close(
+            momentary(&one).unwrap(),
+            momentary(&both).unwrap() - 3.01,
+            0.01,
+        );
+        assert!(momentary(&none).is_some_and(|lufs| lufs < -70.0));
+    }
+
+    #[test]
+    fn out_of_range_gains_are_rejected() {
+        let mut processor = AudioProcessor::new(ProcessorConfig {
+            channel_gains: "2:+3dB".parse().unwrap(),
+            ..Default::default()
+        });
+        assert_eq!(
+            processor.configure(2, RATE),
+            Err(ConfigError::GainChannelOutOfRange {
+                channel: 2,
+                n_channels: 2
+            })
+        );
+    }
+}
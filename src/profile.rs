@@ -0,0 +1,77 @@
+//! Timing the stages a buffer goes through, for `--profile`.
+
+use std::time::{Duration, Instant};
+
+/// Stages of the per-buffer work that are timed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    Decode,
+    Filter,
+    Preemphasis,
+    Agc,
+    Analysis,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [
+        Stage::Decode,
+        Stage::Filter,
+        Stage::Preemphasis,
+        Stage::Agc,
+        Stage::Analysis,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Stage::Decode => "decode",
+            Stage::Filter => "filter",
+            Stage::Preemphasis => "preemphasis",
+            Stage::Agc => "agc",
+            Stage::Analysis => "analysis",
+        }
+    }
+}
+
+/// Time spent in each [`Stage`] since `since`.
+#[derive(Clone, Copy, Debug)]
+pub struct Profile {
+    pub since: Instant,
+    /// Buffers analyzed.
+    pub buffers: u32,
+    /// Total and longest time per stage, indexed by `Stage as usize`.
+    pub total: [Duration; Stage::ALL.len()],
+    pub max: [Duration; Stage::ALL.len()],
+    /// Times each stage ran.
+    pub runs: [u32; Stage::ALL.len()],
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Profile {
+            since: Instant::now(),
+            buffers: 0,
+            total: [Duration::ZERO; Stage::ALL.len()],
+            max: [Duration::ZERO; Stage::ALL.len()],
+            runs: [0; Stage::ALL.len()],
+        }
+    }
+
+    pub fn add(&mut self, stage: Stage, elapsed: Duration) {
+        let i = stage as usize;
+        self.total[i] += elapsed;
+        self.max[i] = self.max[i].max(elapsed);
+        self.runs[i] += 1;
+    }
+
+    /// Mean time `stage` took per run.
+    pub fn average(&self, stage: Stage) -> Duration {
+        let i = stage as usize;
+        self.total[i] / self.runs[i].max(1)
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::new()
+    }
+}
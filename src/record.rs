@@ -0,0 +1,130 @@
+//! Recording the captured samples to a WAV file.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Seconds of audio the writer thread may fall behind before samples are
+/// dropped.
+const BUFFER_SECONDS: usize = 2;
+
+/// How long the writer thread sleeps when it has caught up.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Writes interleaved `f32` samples to a WAV file from a separate thread.
+///
+/// [`push`](Self::push) only copies into a lock-free ring buffer, so it can
+/// be called from the real-time thread; the file is written and finalized
+/// by a writer thread. Dropping the recorder drains what is left, finishes
+/// the WAV header and waits for the writer thread.
+pub struct WavRecorder {
+    n_channels: u16,
+    rate: u32,
+    samples: rtrb::Producer<f32>,
+    /// Samples that didn't fit in the ring buffer.
+    dropped: usize,
+    done: Arc<AtomicBool>,
+    thread: Option<JoinHandle<hound::Result<()>>>,
+}
+
+impl WavRecorder {
+    /// Create `path` for a stream of `n_channels` at `rate` and start the
+    /// writer thread.
+    pub fn create(path: &Path, n_channels: u16, rate: u32) -> hound::Result<Self> {
+        let spec = hound::WavSpec {
+            channels: n_channels,
+            sample_rate: rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let writer = hound::WavWriter::create(path, spec)?;
+        let capacity = rate as usize * n_channels as usize * BUFFER_SECONDS;
+        let (producer, consumer) = rtrb::RingBuffer::new(capacity.max(1));
+        let done = Arc::new(AtomicBool::new(false));
+        let thread_done = done.clone();
+        let thread = thread::Builder::new()
+            .name("wav-recorder".into())
+            .spawn(move || write_samples(writer, consumer, &thread_done))?;
+
+        Ok(WavRecorder {
+            n_channels,
+            rate,
+            samples: producer,
+            dropped: 0,
+            done,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn n_channels(&self) -> u16 {
+        self.n_channels
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Queue interleaved samples for writing. Samples that don't fit because
+    /// the writer thread is behind are dropped and counted.
+    pub fn push(&mut self, samples: &[f32]) {
+        let n = samples.len().min(self.samples.slots());
+        if let Ok(chunk) = self.samples.write_chunk_uninit(n) {
+            chunk.fill_from_iter(samples.iter().copied());
+        }
+        self.dropped += samples.len() - n;
+    }
+
+    /// Samples dropped so far because the writer thread fell behind.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+}
+
+impl Drop for WavRecorder {
+    fn drop(&mut self) {
+        self.done.store(true, Ordering::Release);
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+        match thread.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::error!("failed to write recording: {}", err),
+            Err(_) => tracing::error!("recording thread panicked"),
+        }
+        if self.dropped > 0 {
+            tracing::warn!("recording dropped {} samples", self.dropped);
+        }
+    }
+}
+
+fn write_samples(
+    mut writer: hound::WavWriter<BufWriter<File>>,
+    mut samples: rtrb::Consumer<f32>,
+    done: &AtomicBool,
+) -> hound::Result<()> {
+    loop {
+        // check before draining so nothing pushed before `done` is lost
+        let finished = done.load(Ordering::Acquire);
+        let chunk = samples
+            .read_chunk(samples.slots())
+            .expect("available slots are always readable");
+        let (first, second) = chunk.as_slices();
+        let n = first.len() + second.len();
+        for &sample in first.iter().chain(second) {
+            writer.write_sample(sample)?;
+        }
+        chunk.commit_all();
+
+        if finished {
+            break;
+        }
+        if n == 0 {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+    writer.finalize()
+}
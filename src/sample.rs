@@ -0,0 +1,128 @@
+//! Decoding of raw sample bytes.
+
+/// Replace NaN and infinite samples with silence, so a misbehaving source
+/// can't poison the levels and filter state. Returns how many there were.
+pub fn sanitize(samples: &mut [f32]) -> usize {
+    let mut replaced = 0;
+    for sample in samples.iter_mut().filter(|sample| !sample.is_finite()) {
+        *sample = 0.0;
+        replaced += 1;
+    }
+    replaced
+}
+
+/// Sample encodings the processor knows how to decode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    F32,
+    S32,
+    S16,
+}
+
+impl SampleFormat {
+    /// Size of one sample in bytes.
+    pub fn size(self) -> usize {
+        match self {
+            SampleFormat::F32 => size_of::<f32>(),
+            SampleFormat::S32 => size_of::<i32>(),
+            SampleFormat::S16 => size_of::<i16>(),
+        }
+    }
+
+    /// Decode one little-endian sample, normalizing integers to [-1, 1].
+    ///
+    /// # Panics
+    ///
+    /// If `bytes` is not exactly [`size`](Self::size) bytes long.
+    pub fn decode(self, bytes: &[u8]) -> f32 {
+        match self {
+            SampleFormat::F32 => f32::from_le_bytes(bytes.try_into().unwrap()),
+            SampleFormat::S32 => {
+                i32::from_le_bytes(bytes.try_into().unwrap()) as f32 / -(i32::MIN as f32)
+            }
+            SampleFormat::S16 => {
+                i16::from_le_bytes(bytes.try_into().unwrap()) as f32 / -(i16::MIN as f32)
+            }
+        }
+    }
+
+    /// Decode one data plane of a buffer into interleaved `samples` of
+    /// `n_channels` per frame. The plane carries `plane_channels`
+    /// interleaved channels from `first_channel` on: all of them for an
+    /// interleaved buffer, one for each plane of a planar one. Decodes at
+    /// most `n_frames`, and stops early where the plane or `samples` ends.
+    pub fn decode_plane(
+        self,
+        bytes: &[u8],
+        samples: &mut [f32],
+        n_channels: usize,
+        first_channel: usize,
+        plane_channels: usize,
+        n_frames: usize,
+    ) {
+        if plane_channels == 0 || first_channel + plane_channels > n_channels {
+            return;
+        }
+        let n_frames = n_frames.min(samples.len() / n_channels);
+        for (i, sample) in bytes
+            .chunks_exact(self.size())
+            .take(n_frames * plane_channels)
+            .enumerate()
+        {
+            let channel = first_channel + i % plane_channels;
+            samples[(i / plane_channels) * n_channels + channel] = self.decode(sample);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(samples: &[f32]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn decodes_full_scale() {
+        assert_eq!(SampleFormat::S16.decode(&i16::MIN.to_le_bytes()), -1.0);
+        assert_eq!(SampleFormat::S32.decode(&i32::MIN.to_le_bytes()), -1.0);
+        assert_eq!(SampleFormat::F32.decode(&0.5f32.to_le_bytes()), 0.5);
+    }
+
+    #[test]
+    fn decodes_an_interleaved_plane() {
+        let mut samples = [0.0; 4];
+        let plane = bytes(&[0.1, 0.2, 0.3, 0.4]);
+        SampleFormat::F32.decode_plane(&plane, &mut samples, 2, 0, 2, 2);
+        assert_eq!(samples, [0.1, 0.2, 0.3, 0.4]);
+    }
+
+    #[test]
+    fn interleaves_planar_channels() {
+        let mut samples = [0.0; 6];
+        for (channel, plane) in [[0.1, 0.2], [0.3, 0.4], [0.5, 0.6]].iter().enumerate() {
+            SampleFormat::F32.decode_plane(&bytes(plane), &mut samples, 3, channel, 1, 2);
+        }
+        assert_eq!(samples, [0.1, 0.3, 0.5, 0.2, 0.4, 0.6]);
+    }
+
+    #[test]
+    fn stops_at_the_shorter_end() {
+        // a short plane leaves the rest alone
+        let mut samples = [9.0; 4];
+        SampleFormat::F32.decode_plane(&bytes(&[0.1, 0.2]), &mut samples, 2, 0, 2, 2);
+        assert_eq!(samples, [0.1, 0.2, 9.0, 9.0]);
+        // and a long one doesn't write past `samples`
+        let mut samples = [0.0; 2];
+        SampleFormat::F32.decode_plane(&bytes(&[0.1; 8]), &mut samples, 2, 0, 2, 4);
+        assert_eq!(samples, [0.1, 0.1]);
+    }
+
+    #[test]
+    fn sanitize_replaces_non_finite_samples() {
+        let mut samples = [0.5, f32::NAN, f32::INFINITY, -0.5, f32::NEG_INFINITY];
+        assert_eq!(sanitize(&mut samples), 3);
+        assert_eq!(samples, [0.5, 0.0, 0.0, -0.5, 0.0]);
+    }
+}
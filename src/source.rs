@@ -0,0 +1,24 @@
+//! Sources that are read from, as opposed to PipeWire streams, which push
+//! their buffers at the caller.
+
+use std::time::Duration;
+
+/// Interleaved `f32` frames read on the caller's thread, whether from a
+/// file, a generator or an input device.
+pub trait AudioSource {
+    type Error: std::error::Error;
+
+    fn n_channels(&self) -> usize;
+
+    fn rate(&self) -> u32;
+
+    /// Fill the start of `samples` with as many whole frames as fit and
+    /// are ready, returning the number of frames, or `None` once the source
+    /// has run out. A live source waits up to `timeout` for its first frame
+    /// and returns `Some(0)` if none came.
+    fn read(
+        &mut self,
+        samples: &mut [f32],
+        timeout: Duration,
+    ) -> Result<Option<usize>, Self::Error>;
+}